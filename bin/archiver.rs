@@ -20,16 +20,30 @@ use path_absolutize::*;
 use std::path::Path;
 use std::{env, str::FromStr};
 use substring::Substring;
-use tracing::error;
 use tracing::Level;
+use tracing::{error, info, warn};
 use tracing_subscriber::FmtSubscriber;
 
+const ARCHIVE_MODE: &str = "archive";
 const LS_MODE: &str = "ls";
 const UNARCHIVE_MODE: &str = "unarchive";
+const VERIFY_MODE: &str = "verify";
+const SIGN_MODE: &str = "sign";
+const VERIFY_SIGNATURE_MODE: &str = "verify-signature";
+const UPDATE_MODE: &str = "update";
+const DELETE_MODE: &str = "delete";
+const RESTORE_MODE: &str = "restore";
+const MANIFEST_MODE: &str = "manifest";
+const REPAIR_MODE: &str = "repair";
 
 /// A tool for archive file as tar, but it will compress each file first.
 /// Simple way for gz.tar, archiver ~/files ~/files.gz.tar.
 /// Simple way for ls, archiver ~/files.gz.tar
+/// Simple way for update, archiver update ~/files ~/files.gz.tar
+/// Simple way for delete, archiver delete ~/files.gz.tar a.txt
+/// Simple way for restore, archiver restore ~/full.gz.tar ~/incr.gz.tar -o ~/files
+/// Simple way for sign, archiver sign ~/files.gz.tar --signing-key ~/sign.key
+/// Simple way for repair, archiver repair ~/files.gz.tar
 #[derive(Parser, Debug, Default)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -45,7 +59,8 @@ struct Args {
     /// Glob file pattern
     #[arg(short, long, default_value = "/**/*")]
     pattern: String,
-    /// Run mode, "archive", "ls", "unarchive"
+    /// Run mode, "archive", "ls", "unarchive", "verify", "sign", "verify-signature",
+    /// "update", "delete", "restore", "manifest", "repair"
     #[arg(short, long, default_value = "archive")]
     mode: String,
     /// Unarchive all files to output directory
@@ -54,14 +69,99 @@ struct Args {
     /// Unarchive filter file
     #[arg(short, long)]
     file: Option<String>,
+    /// Paths of entries to delete, the entries under a directory are deleted too
+    #[arg(long = "path")]
+    paths: Vec<String>,
+    /// Previous archive or its manifest json, only the changed files since it are archived
+    #[arg(long)]
+    base: Option<String>,
+    /// Archives to restore, from the full archive to the incremental ones
+    #[arg(long = "chain")]
+    chain: Vec<String>,
+    /// Compress files straight into the archive without temp files
+    #[arg(long)]
+    stream: bool,
+    /// Append files to the existing archive instead of replacing it
+    #[arg(short, long)]
+    append: bool,
+    /// Compression of files, e.g. "zst", it is inferred from the archive file name if not set
+    #[arg(short, long)]
+    compression: Option<String>,
+    /// Count of workers to compress or decompress files
+    #[arg(short, long, default_value_t = 1)]
+    jobs: usize,
+    /// Record the access time of files when archive, and restore it when unarchive
+    #[arg(long)]
+    atime: bool,
+    /// Restore the owner of files, it takes effect when running as root
+    #[arg(long)]
+    same_owner: bool,
+    /// Record extended attributes when archive, and restore them when unarchive
+    #[arg(long)]
+    xattrs: bool,
+    /// Record POSIX ACLs when archive, and restore them when unarchive
+    #[arg(long)]
+    acls: bool,
+    /// Detect the holes of sparse files, only the data regions are archived
+    #[arg(short = 'S', long)]
+    sparse: bool,
+    /// Split files into content-defined chunks, each unique chunk is stored once
+    #[arg(long)]
+    dedup: bool,
+    /// Allow absolute paths, ".." and writing through symlinks for trusted archives
+    #[arg(long)]
+    unsafe_paths: bool,
+    /// Max decompressed size of each entry, e.g. "1GB"
+    #[arg(long)]
+    max_entry_size: Option<bytesize::ByteSize>,
+    /// Max decompressed size of all entries, e.g. "10GB"
+    #[arg(long)]
+    max_total_size: Option<bytesize::ByteSize>,
+    /// Max ratio of decompressed size to compressed size of each entry
+    #[arg(long)]
+    max_ratio: Option<f64>,
+    /// Max count of entries
+    #[arg(long)]
+    max_entries: Option<usize>,
+    /// Do not restore the mode bits of files
+    #[arg(long)]
+    no_same_permissions: bool,
+    /// Do not restore the modification time of files
+    #[arg(long)]
+    touch: bool,
+    /// Passphrase to derive the encryption key, the archive is encrypted if it is set
+    #[arg(long, env = "ARCHIVER_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+    /// Key file of 32 bytes or 64 hex characters, the archive is encrypted if it is set
+    #[arg(long)]
+    key_file: Option<String>,
+    /// Cipher of encryption, "aes-256-gcm" or "chacha20-poly1305"
+    #[arg(long, default_value = "aes-256-gcm")]
+    cipher: String,
+    /// Encrypt the paths of entries too
+    #[arg(long)]
+    encrypt_names: bool,
+    /// Ed25519 signing key file of 32 bytes or 64 hex characters
+    #[arg(long)]
+    signing_key: Option<String>,
+    /// Public key file of the trusted signer
+    #[arg(long)]
+    public_key: Option<String>,
+    /// Accept the signature without "--public-key", it only proves that the archive
+    /// matches its signature file, because anyone can re-sign a modified archive
+    #[arg(long)]
+    allow_untrusted_signer: bool,
+    /// Detached signature file, it is "<archive>.sig" if not set
+    #[arg(long)]
+    signature: Option<String>,
 }
 
 fn init_logger() {
     let mut level = Level::INFO;
-    if let Ok(log_level) = env::var("LOG_LEVEL") {
-        if let Ok(value) = Level::from_str(log_level.as_str()) {
-            level = value;
-        }
+    if let Ok(log_level) = env::var("LOG_LEVEL")
+        && let Ok(value) = Level::from_str(log_level.as_str())
+    {
+        level = value;
     }
     let timer = tracing_subscriber::fmt::time::OffsetTime::local_rfc_3339().unwrap_or_else(|_| {
         tracing_subscriber::fmt::time::OffsetTime::new(
@@ -81,10 +181,10 @@ fn resolve_path(path: &str) -> String {
         return "".to_string();
     }
     let mut p = path.to_string();
-    if p.starts_with('~') {
-        if let Some(home) = dirs::home_dir() {
-            p = home.to_string_lossy().to_string() + p.substring(1, p.len());
-        };
+    if p.starts_with('~')
+        && let Some(home) = dirs::home_dir()
+    {
+        p = home.to_string_lossy().to_string() + p.substring(1, p.len());
     }
     if let Ok(p) = Path::new(&p).absolutize() {
         p.to_string_lossy().to_string()
@@ -94,15 +194,33 @@ fn resolve_path(path: &str) -> String {
 }
 
 fn parse_args() -> Args {
-    let arguments: Vec<String> = env::args().collect();
+    let mut arguments: Vec<String> = env::args().collect();
+    // the mode can be the first argument, e.g. archiver delete ~/files.gz.tar a.txt
+    let command = match arguments.get(1).map(|item| item.as_str()) {
+        Some(
+            UPDATE_MODE
+            | DELETE_MODE
+            | RESTORE_MODE
+            | MANIFEST_MODE
+            | VERIFY_MODE
+            | SIGN_MODE
+            | VERIFY_SIGNATURE_MODE
+            | REPAIR_MODE,
+        ) => Some(arguments.remove(1)),
+        _ => None,
+    };
     let mut args = vec![];
     for (index, item) in arguments.iter().enumerate() {
         if index != 0 && !item.starts_with('-') {
             // 如果上一个参数不是以-开始，而且没有=
             let prev = arguments[index - 1].clone();
             if !prev.starts_with('-') && !prev.contains('=') {
-                if item.ends_with(".tar") {
+                if item.ends_with(".tar") && command.as_deref() == Some(RESTORE_MODE) {
+                    args.push("--chain");
+                } else if item.ends_with(".tar") {
                     args.push("-t");
+                } else if command.as_deref() == Some(DELETE_MODE) {
+                    args.push("--path");
                 } else {
                     args.push("-s");
                 }
@@ -111,10 +229,13 @@ fn parse_args() -> Args {
         args.push(item)
     }
     let mut args = Args::parse_from(args);
-    if args.output.is_some() || args.file.is_some() {
+    if let Some(command) = command {
+        args.mode = command;
+    }
+    if args.mode != RESTORE_MODE && (args.output.is_some() || args.file.is_some()) {
         args.mode = UNARCHIVE_MODE.to_string();
     }
-    if args.mode != UNARCHIVE_MODE && args.source.clone().unwrap_or_default().is_empty() {
+    if args.mode == ARCHIVE_MODE && args.source.clone().unwrap_or_default().is_empty() {
         args.mode = LS_MODE.to_string()
     }
     args
@@ -126,25 +247,145 @@ async fn run() -> Result<(), Error> {
     let source = resolve_path(&args.source.unwrap_or_default());
     let target = resolve_path(&args.tar.unwrap_or_default());
     let output = resolve_path(&args.output.unwrap_or_default());
+    let compression = args
+        .compression
+        .map(|value| value.parse::<archiver::Compression>())
+        .transpose()?;
+    let key = match (args.key_file, args.passphrase) {
+        (Some(file), _) => Some(archiver::KeySource::File(resolve_path(&file).into())),
+        (None, Some(passphrase)) => Some(archiver::KeySource::Passphrase(passphrase)),
+        _ => None,
+    };
 
     match args.mode.as_str() {
-        LS_MODE => archiver::ls(&target).await,
-        UNARCHIVE_MODE => {
-            archiver::unarchive(archiver::UnarchiveParams {
+        LS_MODE => archiver::ls_with_key(&target, key.as_ref()).await,
+        VERIFY_MODE => {
+            let items = archiver::verify(&target, compression, key.as_ref()).await?;
+            let corrupted: Vec<_> = items.iter().filter(|item| !item.is_valid()).collect();
+            for item in corrupted.iter() {
+                error!(
+                    file = item.path,
+                    message = item.error.clone().unwrap_or_default()
+                );
+            }
+            if !corrupted.is_empty() {
+                return Err(Error::Corrupted {
+                    count: corrupted.len(),
+                });
+            }
+            info!(file_count = items.len(), "verify success");
+            Ok(())
+        }
+        SIGN_MODE => {
+            let key = resolve_path(&args.signing_key.unwrap_or_default());
+            if key.is_empty() {
+                return Err(Error::InvalidArg { path: key });
+            }
+            let signature = args.signature.map(|path| resolve_path(&path));
+            archiver::sign(&target, Path::new(&key), signature.as_deref()).await?;
+            Ok(())
+        }
+        VERIFY_SIGNATURE_MODE => {
+            let signature = args.signature.map(|path| resolve_path(&path));
+            let public_key = args.public_key.map(|path| resolve_path(&path));
+            let fingerprint = archiver::verify_signature(
+                &target,
+                signature.as_deref(),
+                public_key.as_deref().map(Path::new),
+            )
+            .await?;
+            if public_key.is_none() {
+                warn!(
+                    fingerprint,
+                    "signature is self-consistent, signer is not trusted"
+                );
+                if !args.allow_untrusted_signer {
+                    return Err(Error::UntrustedSigner { fingerprint });
+                }
+                return Ok(());
+            }
+            info!(fingerprint, "signature is valid");
+            Ok(())
+        }
+        MANIFEST_MODE => {
+            let Some(manifest) = archiver::read_manifest(&target).await? else {
+                return Err(Error::InvalidArg { path: target });
+            };
+            // the sealed manifest is printed as it is, so it can be the base of incremental archive
+            println!("{}", String::from_utf8_lossy(&manifest.to_json()?));
+            Ok(())
+        }
+        REPAIR_MODE => {
+            if archiver::repair(&target).await? {
+                info!(file = target, "repair success");
+            } else {
+                info!(file = target, "archive is not interrupted");
+            }
+            Ok(())
+        }
+        DELETE_MODE => {
+            let count = archiver::delete(&target, &args.paths, compression, key.as_ref()).await?;
+            info!(file = target, count, "delete success");
+            Ok(())
+        }
+        UNARCHIVE_MODE | RESTORE_MODE => {
+            let params = archiver::UnarchiveParams {
                 source: target,
                 target: output,
                 file: args.file.unwrap_or_default(),
-            })
-            .await
+                compression,
+                jobs: args.jobs,
+                preserve_permissions: !args.no_same_permissions,
+                same_owner: args.same_owner,
+                preserve_mtime: !args.touch,
+                preserve_atime: args.atime,
+                xattrs: args.xattrs,
+                acls: args.acls,
+                unsafe_paths: args.unsafe_paths,
+                max_entry_size: args.max_entry_size.map(|size| size.as_u64()),
+                max_total_size: args.max_total_size.map(|size| size.as_u64()),
+                max_ratio: args.max_ratio,
+                max_entries: args.max_entries,
+                key,
+            };
+            if args.mode == RESTORE_MODE {
+                let chain: Vec<String> = args.chain.iter().map(|path| resolve_path(path)).collect();
+                archiver::restore(&chain, params).await
+            } else {
+                archiver::unarchive(params).await
+            }
         }
         _ => {
-            archiver::archive(archiver::ArchiveParams {
+            let params = archiver::ArchiveParams {
                 source,
                 target,
                 level: args.level,
                 pattern: args.pattern,
-            })
-            .await
+                stream: args.stream,
+                compression,
+                jobs: args.jobs,
+                atime: args.atime,
+                xattrs: args.xattrs,
+                acls: args.acls,
+                sparse: args.sparse,
+                dedup: args.dedup,
+                encryption: key
+                    .map(|key| {
+                        Ok::<_, Error>(archiver::Encryption {
+                            cipher: args.cipher.parse()?,
+                            key,
+                            names: args.encrypt_names,
+                        })
+                    })
+                    .transpose()?,
+                append: args.append,
+                base: args.base.map(|path| resolve_path(&path)),
+            };
+            if args.mode == UPDATE_MODE {
+                archiver::update(params).await
+            } else {
+                archiver::archive(params).await
+            }
         }
     }
 }
@@ -159,5 +400,6 @@ fn main() {
     init_logger();
     if let Err(e) = run() {
         error!(message = e.to_string());
+        std::process::exit(1);
     }
 }
@@ -13,68 +13,273 @@
 // limitations under the License.
 
 use chrono::{DateTime, Local};
+use filetime::{FileTime, set_file_times, set_symlink_file_times};
 use glob::glob;
 use pad::{Alignment, PadStr};
-use std::path::Path;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::SystemTime;
+use tempfile::TempPath;
+use tokio::fs;
 use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, ReadBuf};
+use tokio::task::{JoinHandle, JoinSet};
 use tokio_stream::StreamExt;
-use tokio_tar::{Archive, Builder};
-use tracing::{debug, info};
+use tokio_tar::{Builder, EntryType, Header};
+use tracing::{debug, info, warn};
 use uuid::{NoContext, Timestamp, Uuid};
 
-use super::compression;
+use super::codec::{Codec, Compression, Reader, Writer};
+use super::crypto::{Crypter, Encryption, KeySource, entry_codec, open_manifest};
+use super::dedup::{
+    CHUNK_DIR, ChunkWriter, chunked_size, drop_unused_chunks, is_chunk, resolve_codec,
+};
 use super::error::Error;
+use super::incremental::{Incremental, changed_entries};
+use super::index::{
+    ArchiveIndex, INDEX_VERSION, IndexEntry, append_index, header_offset, index_entry,
+    index_offset, is_metadata, read_index, scan_index, stream_position,
+};
+use super::limit::{EntryLimits, Limit, Limits};
+use super::manifest::{
+    HashReader, HashWriter, MANIFEST_PATH, MANIFEST_VERSION, Manifest, ManifestFile,
+    manifest_mtime, open_archive, open_archive_at, read_manifest, resolve_compression,
+};
+use super::pax::{PAX_ATIME, PAX_MTIME, PaxRecords, append_pax, format_time, parse_time, read_pax};
+use super::sparse::{SparseMap, SparseReader, SparseWriter, sparse_map};
+use super::xattrs::{apply_xattrs, is_xattr_record, read_xattrs};
 
-const GZIP: &str = "gz";
-const ZSTD: &str = "zst";
-const BROTLI: &str = "br";
-const LZ4: &str = "lz4";
-const SNAPPY: &str = "sz";
-const DEFLATE: &str = "zip";
-const XZ: &str = "xz";
+const BLOCK_SIZE: u64 = 512;
+// entries larger than this are decoded inline to keep memory bounded
+const PARALLEL_ENTRY_SIZE: u64 = 8 * 1024 * 1024;
 
-fn uuid() -> String {
+pub(crate) fn uuid() -> String {
     let ts = Timestamp::now(NoContext);
     Uuid::new_v7(ts).to_string()
 }
 
+/// Temp path beside the path, the output is written to it and renamed
+/// on success, so a partial output is never left at the path.
+/// It is removed when the `TempPath` is dropped without persisting.
+fn temp_path(path: &Path) -> PathBuf {
+    path.with_file_name(format!(".archiver-{}.tmp", uuid()))
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct ArchiveParams {
     pub source: String,
     pub target: String,
     pub level: i32,
     pub pattern: String,
+    /// Compression of files, it is inferred from the target file name
+    /// `name.<codec>.tar` if not set.
+    pub compression: Option<Compression>,
+    /// Compress each file straight into the tar entry instead of a temp file,
+    /// the entry header is patched with the compressed size afterwards.
+    pub stream: bool,
+    /// Count of workers to compress files concurrently, it is not used in stream mode.
+    pub jobs: usize,
+    /// Record the access time of files. It is changed when the files are read,
+    /// so the archive is not reproducible with it.
+    pub atime: bool,
+    /// Record the extended attributes of entries as PAX records.
+    pub xattrs: bool,
+    /// Record the POSIX ACLs of entries as PAX records.
+    pub acls: bool,
+    /// Detect the holes of sparse files, only the data regions are compressed.
+    pub sparse: bool,
+    /// Encrypt the compressed data of each file, and the paths optionally.
+    pub encryption: Option<Encryption>,
+    /// Append the files to the existing archive instead of replacing it,
+    /// the compression and encryption of the archive are used. The archive
+    /// is locked while appending, and an interrupted append is rolled back first.
+    pub append: bool,
+    /// Previous archive or its manifest json, only the files changed since it
+    /// are archived and the deleted entries are recorded as tombstones.
+    pub base: Option<String>,
+    /// Split files into content-defined chunks, and store each unique chunk
+    /// once. It is not compatible with the stream and sparse mode.
+    pub dedup: bool,
 }
+
+#[derive(Debug, Clone)]
 pub struct UnarchiveParams {
     pub source: String,
     pub target: String,
     pub file: String,
+    /// Compression of files, it is inferred from the source file name
+    /// `name.<codec>.tar` if not set.
+    pub compression: Option<Compression>,
+    /// Count of workers to decompress files concurrently.
+    pub jobs: usize,
+    /// Restore the mode bits of files and directories,
+    /// the setuid and setgid bits are only restored with `same_owner`.
+    pub preserve_permissions: bool,
+    /// Restore the uid and gid of entries, it requires root privilege.
+    pub same_owner: bool,
+    /// Restore the modification time with sub-second precision if it is recorded.
+    pub preserve_mtime: bool,
+    /// Restore the access time if it is recorded in the archive.
+    pub preserve_atime: bool,
+    /// Restore the extended attributes recorded in the archive.
+    pub xattrs: bool,
+    /// Restore the POSIX ACLs recorded in the archive.
+    pub acls: bool,
+    /// Allow absolute paths, `..` and writing through symlinks,
+    /// it should only be used for trusted archives.
+    pub unsafe_paths: bool,
+    /// Max decompressed size of each entry.
+    pub max_entry_size: Option<u64>,
+    /// Max decompressed size of all entries.
+    pub max_total_size: Option<u64>,
+    /// Max ratio of decompressed size to compressed size of each entry,
+    /// it is checked once the decompressed size exceeds 1 MiB.
+    pub max_ratio: Option<f64>,
+    /// Max count of entries.
+    pub max_entries: Option<usize>,
+    /// Key of the encrypted archive.
+    pub key: Option<KeySource>,
 }
 
-pub async fn ls(target: &str) -> Result<(), Error> {
+impl Default for UnarchiveParams {
+    fn default() -> Self {
+        Self {
+            source: String::new(),
+            target: String::new(),
+            file: String::new(),
+            compression: None,
+            jobs: 0,
+            preserve_permissions: true,
+            same_owner: false,
+            preserve_mtime: true,
+            preserve_atime: false,
+            xattrs: false,
+            acls: false,
+            unsafe_paths: false,
+            max_entry_size: None,
+            max_total_size: None,
+            max_ratio: None,
+            max_entries: None,
+            key: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ArchiveEntry {
+    pub path: String,
+    pub mode: Option<u32>,
+    pub mtime: Option<u64>,
+    /// Original size of the file, it is unknown for archives without manifest
+    pub size: Option<u64>,
+    pub compressed_size: Option<u64>,
+    /// Target of symlink or hard link
+    pub link: Option<String>,
+}
+
+impl ArchiveEntry {
+    /// Compressed size divided by original size.
+    pub fn ratio(&self) -> Option<f64> {
+        match (self.size, self.compressed_size) {
+            (Some(size), Some(compressed_size)) if size > 0 => {
+                Some(compressed_size as f64 / size as f64)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn format_size(size: Option<u64>) -> String {
+    size.map(|size| bytesize::ByteSize(size).to_string())
+        .unwrap_or_else(|| "--".to_string())
+        .pad_to_width_with_alignment(10, Alignment::Right)
+}
+
+fn format_ratio(ratio: Option<f64>) -> String {
+    ratio
+        .map(|ratio| format!("{:.1}%", ratio * 100.0))
+        .unwrap_or_else(|| "--".to_string())
+        .pad_to_width_with_alignment(7, Alignment::Right)
+}
+
+/// List the entries of archive, the original size of each file
+/// is read from the manifest.
+pub async fn list(target: &str) -> Result<Vec<ArchiveEntry>, Error> {
+    list_with_key(target, None).await
+}
+
+/// List the entries of archive with the key of encrypted archive.
+/// Without the key, the paths are listed as they are stored and
+/// the original sizes are unknown.
+pub async fn list_with_key(
+    target: &str,
+    key: Option<&KeySource>,
+) -> Result<Vec<ArchiveEntry>, Error> {
     if target.is_empty() {
         return Err(Error::InvalidArg {
             path: target.to_string(),
         });
     }
-    let file = File::open(target).await?;
-    let mut r = Archive::new(file);
+    let mut manifest = read_manifest(target).await?;
+    let crypter = match key {
+        Some(key) => open_manifest(manifest.as_mut(), Some(key))?,
+        None => None,
+    };
+    let mut r = open_archive(target)?;
     let mut entries = r.entries()?;
-    let mut lines = vec![];
+    let mut items = vec![];
     while let Some(file) = entries.next().await {
-        let f = file?;
-        let size = if let Ok(size) = f.header().size() {
-            bytesize::ByteSize(size).to_string()
-        } else {
-            "--".to_string()
+        let mut f = file?;
+        let path = f.path()?.to_string_lossy().to_string();
+        if is_metadata(&path) || is_chunk(&path) {
+            continue;
         }
-        .pad_to_width_with_alignment(8, Alignment::Right);
-        let mode = if let Ok(mode) = f.header().mode() {
+        let pax = read_pax(&mut f).await?;
+        let path = decrypt_name(crypter.as_ref(), Path::new(&path))?
+            .to_string_lossy()
+            .to_string();
+        let size = manifest
+            .as_ref()
+            .and_then(|manifest| manifest.file(&path))
+            .map(|file| file.size);
+        items.push(ArchiveEntry {
+            path,
+            mode: f.header().mode().ok(),
+            mtime: f.header().mtime().ok(),
+            size,
+            compressed_size: chunked_size(&pax)?.or(f.header().size().ok()),
+            link: f
+                .link_name()
+                .ok()
+                .flatten()
+                .map(|link| decrypt_name(crypter.as_ref(), &link))
+                .transpose()?
+                .map(|link| link.to_string_lossy().to_string()),
+        });
+    }
+    Ok(items)
+}
+
+pub async fn ls(target: &str) -> Result<(), Error> {
+    ls_with_key(target, None).await
+}
+
+pub async fn ls_with_key(target: &str, key: Option<&KeySource>) -> Result<(), Error> {
+    let items = list_with_key(target, key).await?;
+    let mut lines = vec![];
+    // the original size is unknown for archives without manifest
+    let mut total = ArchiveEntry::default();
+    for item in items.iter() {
+        let mode = if let Some(mode) = item.mode {
             unix_mode::to_string(mode)
         } else {
             "--".to_string()
         };
-        let mtime = if let Ok(mtime) = f.header().mtime() {
+        let mtime = if let Some(mtime) = item.mtime {
             let mtime: DateTime<Local> = DateTime::from_timestamp(mtime as i64, 0)
                 .unwrap_or_default()
                 .into();
@@ -83,11 +288,33 @@ pub async fn ls(target: &str) -> Result<(), Error> {
             "--".to_string()
         }
         .pad_to_width_with_alignment(19, Alignment::Right);
+        if let Some(size) = item.size {
+            total.size = Some(total.size.unwrap_or_default() + size);
+        }
+        if let Some(size) = item.compressed_size {
+            total.compressed_size = Some(total.compressed_size.unwrap_or_default() + size);
+        }
 
-        lines.push(format!("{mode}  {size}  {mtime}  {}", f.path()?.display(),));
+        lines.push(format!(
+            "{mode}  {}  {}  {}  {mtime}  {}",
+            format_size(item.size),
+            format_size(item.compressed_size),
+            format_ratio(item.ratio()),
+            if let Some(link) = &item.link {
+                format!("{} -> {link}", item.path)
+            } else {
+                item.path.clone()
+            },
+        ));
     }
 
-    println!("total {}", lines.len());
+    println!(
+        "total {}, size {}, compressed {}, ratio {}",
+        lines.len(),
+        format_size(total.size).trim(),
+        format_size(total.compressed_size).trim(),
+        format_ratio(total.ratio()).trim(),
+    );
 
     for line in lines {
         println!("{line}");
@@ -96,22 +323,306 @@ pub async fn ls(target: &str) -> Result<(), Error> {
     Ok(())
 }
 
+/// Metadata of the entry restored after it is extracted,
+/// the fields are none if they are not recorded or not to be restored.
+#[derive(Debug, Clone, Default)]
+struct Attributes {
+    mode: Option<u32>,
+    owner: Option<(u64, u64)>,
+    mtime: Option<FileTime>,
+    atime: Option<FileTime>,
+    /// PAX records of extended attributes and ACLs
+    xattrs: PaxRecords,
+}
+
+impl Attributes {
+    fn new(header: &Header, pax: &PaxRecords, params: &UnarchiveParams) -> Self {
+        let mut attrs = Attributes::default();
+        if params.preserve_permissions
+            && let Ok(mode) = header.mode()
+        {
+            // setuid and setgid are dropped if the owner is not restored
+            attrs.mode = Some(mode & if params.same_owner { 0o7777 } else { 0o1777 });
+        }
+        if params.same_owner
+            && let (Ok(uid), Ok(gid)) = (header.uid(), header.gid())
+        {
+            attrs.owner = Some((uid, gid));
+        }
+        if params.preserve_mtime {
+            attrs.mtime = pax
+                .get(PAX_MTIME)
+                .and_then(|value| parse_time(value))
+                .or_else(|| {
+                    let mtime = header.mtime().ok()?;
+                    Some(FileTime::from_unix_time(mtime as i64, 0))
+                });
+        }
+        if params.preserve_atime {
+            attrs.atime = pax.get(PAX_ATIME).and_then(|value| parse_time(value));
+        }
+        attrs.xattrs = pax
+            .iter()
+            .filter(|(key, _)| is_xattr_record(key, params.xattrs, params.acls))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        attrs
+    }
+
+    /// Restore the metadata of the path, the mode of symlink is not changed.
+    fn apply(&self, path: &Path, symlink: bool) -> Result<(), Error> {
+        // chown clears the setuid and setgid bits, so it is called before chmod
+        #[cfg(unix)]
+        if let Some((uid, gid)) = self.owner
+            && let Err(err) = std::os::unix::fs::lchown(path, Some(uid as u32), Some(gid as u32))
+        {
+            warn!(
+                file = path.to_string_lossy().to_string(),
+                error = err.to_string(),
+                "restore owner fail"
+            );
+        }
+        #[cfg(unix)]
+        if let Some(mode) = self.mode
+            && !symlink
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        // the access ACL is applied after chmod, which changes its mask
+        apply_xattrs(path, &self.xattrs);
+        if self.mtime.is_none() && self.atime.is_none() {
+            return Ok(());
+        }
+        let meta = std::fs::symlink_metadata(path)?;
+        let mtime = self
+            .mtime
+            .unwrap_or_else(|| FileTime::from_last_modification_time(&meta));
+        let atime = self
+            .atime
+            .unwrap_or_else(|| FileTime::from_last_access_time(&meta));
+        if symlink {
+            set_symlink_file_times(path, atime, mtime)?;
+        } else {
+            set_file_times(path, atime, mtime)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the decoded data to the file, the holes of sparse file are not written.
+async fn write_file(
+    codec: &dyn Codec,
+    reader: &mut Reader<'_>,
+    file_path: &Path,
+    sparse: Option<SparseMap>,
+    limits: EntryLimits,
+) -> Result<u64, Error> {
+    if let Some(map) = sparse {
+        limits.reserve_holes(map.size, map.size - map.data_size())?;
+        let mut w = limits.writer(SparseWriter::new(std::fs::File::create(file_path)?, map));
+        let max_size = w.max_size();
+        let result = codec.decode_limited(reader, &mut w, max_size).await;
+        w.check(result)?;
+        return w.into_inner().finish();
+    }
+    let mut w = limits.writer(File::create(file_path).await?);
+    let max_size = w.max_size();
+    let result = codec.decode_limited(reader, &mut w, max_size).await;
+    let size = w.check(result)?;
+    w.flush().await?;
+    Ok(size)
+}
+
+/// Decode the entry data to a temp file and restore its metadata, then
+/// rename it to the file path. The temp file is removed if it fails.
+async fn decode_file(
+    codec: &dyn Codec,
+    reader: &mut Reader<'_>,
+    file_path: &Path,
+    attrs: &Attributes,
+    sparse: Option<SparseMap>,
+    limits: EntryLimits,
+) -> Result<u64, Error> {
+    if let Some(dir) = file_path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let temp = TempPath::try_from_path(temp_path(file_path))?;
+    let size = write_file(codec, reader, &temp, sparse, limits).await?;
+    attrs.apply(&temp, false)?;
+    temp.persist(file_path).map_err(|err| err.error)?;
+    Ok(size)
+}
+
+/// Decode the entry data to the writer, returns the decoded size.
+/// The sparse file is decoded to a temp file first, and the holes
+/// are filled with zeros when the temp file is read.
+pub(crate) async fn write_entry(
+    codec: &dyn Codec,
+    reader: &mut Reader<'_>,
+    writer: &mut Writer<'_>,
+    sparse: Option<SparseMap>,
+    limits: EntryLimits,
+) -> Result<u64, Error> {
+    if let Some(map) = sparse {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join(uuid());
+        decode_file(
+            codec,
+            reader,
+            &file,
+            &Attributes::default(),
+            Some(map),
+            limits,
+        )
+        .await?;
+        return Ok(tokio::io::copy(&mut File::open(&file).await?, writer).await?);
+    }
+    let mut w = limits.writer(writer);
+    let max_size = w.max_size();
+    let result = codec.decode_limited(reader, &mut w, max_size).await;
+    w.check(result)
+}
+
+/// Decrypt the path of entry if the names of archive are encrypted.
+pub(crate) fn decrypt_name(crypter: Option<&Crypter>, name: &Path) -> Result<PathBuf, Error> {
+    match crypter {
+        Some(crypter) => crypter.decrypt_name(name),
+        None => Ok(name.to_path_buf()),
+    }
+}
+
+/// Encrypt the path of entry if the names of archive are encrypted.
+fn encrypt_name(crypter: Option<&Crypter>, name: &Path) -> Result<PathBuf, Error> {
+    match crypter {
+        Some(crypter) => crypter.encrypt_name(name),
+        None => Ok(name.to_path_buf()),
+    }
+}
+
+fn link_name<R>(f: &tokio_tar::Entry<R>, crypter: Option<&Crypter>) -> Result<PathBuf, Error>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let link = f.link_name()?.ok_or_else(|| Error::InvalidArg {
+        path: f
+            .path()
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    })?;
+    decrypt_name(crypter, &link)
+}
+
+/// Remove the existing file, so that the link can be created.
+async fn remove_existing(path: &Path) -> Result<(), Error> {
+    if let Ok(meta) = fs::symlink_metadata(path).await
+        && !meta.is_dir()
+    {
+        fs::remove_file(path).await?;
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn create_symlink(link: &Path, path: &Path) -> Result<(), Error> {
+    remove_existing(path).await?;
+    fs::symlink(link, path).await?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn create_symlink(link: &Path, path: &Path) -> Result<(), Error> {
+    warn!(
+        file = path.to_string_lossy().to_string(),
+        link = link.to_string_lossy().to_string(),
+        "symlink is not supported"
+    );
+    Ok(())
+}
+
+async fn create_hard_link(link: &Path, path: &Path) -> Result<(), Error> {
+    remove_existing(path).await?;
+    fs::hard_link(link, path).await?;
+    Ok(())
+}
+
+fn is_symlink(path: &Path) -> bool {
+    std::fs::symlink_metadata(path)
+        .map(|meta| meta.file_type().is_symlink())
+        .unwrap_or_default()
+}
+
+/// Join the entry path to the output directory. The path must be relative
+/// without `..`, and its parent directories must not be symlinks, otherwise
+/// the file may be written outside of the output directory.
+pub(crate) fn safe_join(output: &Path, path: &Path, unsafe_paths: bool) -> Result<PathBuf, Error> {
+    if unsafe_paths {
+        return Ok(output.join(path));
+    }
+    let unsafe_path = |reason: &str| Error::UnsafePath {
+        path: path.to_string_lossy().to_string(),
+        reason: reason.to_string(),
+    };
+    let mut file_path = output.to_path_buf();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => {
+                if file_path != output && is_symlink(&file_path) {
+                    return Err(unsafe_path("parent directory is symlink"));
+                }
+                file_path.push(name);
+            }
+            Component::CurDir => {}
+            Component::ParentDir => return Err(unsafe_path("parent directory is not allowed")),
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(unsafe_path("absolute path is not allowed"));
+            }
+        }
+    }
+    if file_path == output {
+        return Err(unsafe_path("path is empty"));
+    }
+    Ok(file_path)
+}
+
+/// Wait for all decoding workers to finish.
+async fn join_workers(workers: &mut JoinSet<Result<u64, Error>>) -> Result<(), Error> {
+    while let Some(result) = workers.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+/// Unarchive files to the target directory, files are decoded and written
+/// as stream. If only a filter file is specified without target,
+/// the file is printed to stdout.
 pub async fn unarchive(params: UnarchiveParams) -> Result<(), Error> {
     if params.source.is_empty() {
         return Err(Error::InvalidArg {
             path: params.source,
         });
     }
-    let arr: Vec<&str> = params.source.split('.').collect();
-    if arr.len() < 3 {
-        return Err(Error::InvalidArg {
-            path: params.source,
-        });
-    }
-    let compress_type = arr[arr.len() - 2];
+    let mut manifest = read_manifest(&params.source).await?;
+    let compression = resolve_compression(
+        &params.source,
+        params.compression.clone(),
+        manifest.as_ref(),
+    )?;
+    let crypter = open_manifest(manifest.as_mut(), params.key.as_ref())?;
 
-    let file = File::open(&params.source).await?;
-    let mut r = Archive::new(file);
+    // the filter file is read from its entry found by the index
+    let mut r = if params.file.is_empty() {
+        open_archive(&params.source)?
+    } else {
+        let index = read_index(&params.source).await?;
+        let Some(offset) = header_offset(index.as_ref(), &params.file, crypter.as_ref())? else {
+            return Ok(());
+        };
+        open_archive_at(&params.source, offset)?
+    };
     let mut entries = r.entries()?;
     let output = if params.target.is_empty() {
         Path::new(&params.source)
@@ -124,36 +635,164 @@ pub async fn unarchive(params: UnarchiveParams) -> Result<(), Error> {
     };
     let mut file_count = 0;
     let start = SystemTime::now();
+    let jobs = params.jobs.max(1);
+    let mut workers = JoinSet::new();
+    let mut dirs = vec![];
+    let mut store = None;
+    let limits = Limits {
+        entry_size: params.max_entry_size,
+        total_size: params.max_total_size,
+        ratio: params.max_ratio,
+        ..Default::default()
+    };
 
-    while let Some(file) = entries.next().await {
+    // the scan stops once the filter file is found
+    let mut found = false;
+    while !found && let Some(file) = entries.next().await {
         let mut f = file?;
-        let path = f.path()?;
-        if !params.file.is_empty() && params.file != path.to_string_lossy() {
+        let path = f.path()?.to_path_buf();
+        if is_metadata(&path.to_string_lossy()) || is_chunk(&path.to_string_lossy()) {
             continue;
         }
+        let path = decrypt_name(crypter.as_ref(), &path)?;
+        if !params.file.is_empty() {
+            if params.file != path.to_string_lossy() {
+                continue;
+            }
+            found = true;
+        }
         file_count += 1;
+        if let Some(max) = params.max_entries
+            && file_count > max
+        {
+            return Err(Error::LimitExceeded {
+                path: path.to_string_lossy().to_string(),
+                limit: Limit::Entries(max),
+            });
+        }
+        let entry_type = f.header().entry_type();
+        let pax = read_pax(&mut f).await?;
+        let attrs = Attributes::new(f.header(), &pax, &params);
+        let sparse = SparseMap::from_records(&pax)?;
+        // print the filter file if no output directory is specified
+        let print = !params.file.is_empty() && params.target.is_empty();
+        if !entry_type.is_file() {
+            if print {
+                continue;
+            }
+            match entry_type {
+                EntryType::Directory => {
+                    // the paths of decoding files are checked before the
+                    // directory or symlink is created, so wait for them
+                    join_workers(&mut workers).await?;
+                    let dir = safe_join(output, &path, params.unsafe_paths)?;
+                    // the metadata of directory is applied to the symlink target
+                    if !params.unsafe_paths && is_symlink(&dir) {
+                        return Err(Error::UnsafePath {
+                            path: path.to_string_lossy().to_string(),
+                            reason: "directory is symlink".to_string(),
+                        });
+                    }
+                    fs::create_dir_all(&dir).await?;
+                    dirs.push((dir, attrs));
+                }
+                EntryType::Symlink => {
+                    join_workers(&mut workers).await?;
+                    let link = link_name(&f, crypter.as_ref())?;
+                    let file_path = safe_join(output, &path, params.unsafe_paths)?;
+                    create_symlink(&link, &file_path).await?;
+                    #[cfg(unix)]
+                    attrs.apply(&file_path, true)?;
+                }
+                EntryType::Link => {
+                    // the linked file may be decoding by workers
+                    join_workers(&mut workers).await?;
+                    let link = safe_join(
+                        output,
+                        &link_name(&f, crypter.as_ref())?,
+                        params.unsafe_paths,
+                    )?;
+                    let file_path = safe_join(output, &path, params.unsafe_paths)?;
+                    create_hard_link(&link, &file_path).await?;
+                }
+                _ => {
+                    warn!(
+                        file = path.to_string_lossy().to_string(),
+                        entry_type = format!("{entry_type:?}"),
+                        "entry type is not supported"
+                    );
+                }
+            }
+            continue;
+        }
+        let size = f.header().size()?;
+        // the ratio of chunked file is checked with the size of its chunks
+        let (codec, compressed) = resolve_codec(
+            &pax,
+            &path.to_string_lossy(),
+            &mut f,
+            &mut store,
+            &params.source,
+            &compression,
+            crypter.as_ref(),
+        )
+        .await?;
+        let entry_limits = limits.entry(&path.to_string_lossy(), compressed.unwrap_or(size));
+        if print {
+            let mut w = tokio::io::stdout();
+            write_entry(codec.as_ref(), &mut f, &mut w, sparse, entry_limits).await?;
+            w.flush().await?;
+            continue;
+        }
 
-        let file_path = output.join(path);
+        let file_path = safe_join(output, &path, params.unsafe_paths)?;
+        // the file is created through the existing symlink
+        if !params.unsafe_paths && is_symlink(&file_path) {
+            fs::remove_file(&file_path).await?;
+        }
         debug!(
             file = file_path.to_string_lossy().to_string(),
             "start to decode"
         );
-        let filename = &Some(file_path);
-        let buf = match compress_type {
-            GZIP => compression::gzip_decode(&mut f, filename).await,
-            ZSTD => compression::zstd_decode(&mut f, filename).await,
-            BROTLI => compression::brotli_decode(&mut f, filename).await,
-            SNAPPY => compression::snappy_decode(&mut f, filename).await,
-            LZ4 => compression::lz4_decode(&mut f, filename).await,
-            DEFLATE => compression::deflate_decode(&mut f, filename).await,
-            XZ => compression::xz_decode(&mut f, filename).await,
-            _ => Err(Error::InvalidCompression {
-                compression: compress_type.to_string(),
-            }),
-        }?;
-        if !params.file.is_empty() {
-            println!("{}", std::string::String::from_utf8_lossy(&buf));
+        if jobs <= 1 || size > PARALLEL_ENTRY_SIZE {
+            decode_file(
+                codec.as_ref(),
+                &mut f,
+                &file_path,
+                &attrs,
+                sparse,
+                entry_limits,
+            )
+            .await?;
+            continue;
         }
+        // small entries are read into memory and decoded by workers,
+        // so the tar stream can be read while they are decoding
+        while workers.len() >= jobs {
+            if let Some(result) = workers.join_next().await {
+                result??;
+            }
+        }
+        let mut data = Vec::with_capacity(size as usize);
+        f.read_to_end(&mut data).await?;
+        let codec = codec.clone();
+        workers.spawn(async move {
+            decode_file(
+                codec.as_ref(),
+                &mut &data[..],
+                &file_path,
+                &attrs,
+                sparse,
+                entry_limits,
+            )
+            .await
+        });
+    }
+    join_workers(&mut workers).await?;
+    // the mtime of directory is changed when files are created in it,
+    // and files can't be created in a read-only directory
+    for (dir, attrs) in dirs.iter().rev() {
+        attrs.apply(dir, false)?;
     }
     let mut duration = None;
     if let Ok(d) = SystemTime::now().duration_since(start) {
@@ -169,6 +808,975 @@ pub async fn unarchive(params: UnarchiveParams) -> Result<(), Error> {
     Ok(())
 }
 
+/// Result of verifying the file of archive.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerifyEntry {
+    pub path: String,
+    /// Decoded size of the file
+    pub size: u64,
+    /// Blake3 checksum of the decoded file
+    pub checksum: String,
+    /// Checksum recorded in the manifest, it is none for archives without manifest
+    pub expected: Option<String>,
+    /// Reason of the failure, e.g. decode error or checksum mismatch
+    pub error: Option<String>,
+}
+
+impl VerifyEntry {
+    pub fn is_valid(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Decode every file of archive and compare its size and checksum with
+/// the manifest. The decoded data is only hashed, nothing is written to disk.
+/// The files of manifest which are missing from the archive are reported too.
+/// It fails if the archive is being appended or its append is interrupted.
+pub async fn verify(
+    target: &str,
+    compression: Option<Compression>,
+    key: Option<&KeySource>,
+) -> Result<Vec<VerifyEntry>, Error> {
+    if target.is_empty() {
+        return Err(Error::InvalidArg {
+            path: target.to_string(),
+        });
+    }
+    let mut manifest = read_manifest(target).await?;
+    let compression = resolve_compression(target, compression, manifest.as_ref())?;
+    let crypter = open_manifest(manifest.as_mut(), key)?;
+    let mut store = None;
+    let mut r = open_archive(target)?;
+    let mut entries = r.entries()?;
+    let mut items = vec![];
+    while let Some(file) = entries.next().await {
+        let mut f = file?;
+        let path = f.path()?.to_string_lossy().to_string();
+        if is_metadata(&path) || is_chunk(&path) || !f.header().entry_type().is_file() {
+            continue;
+        }
+        let path = decrypt_name(crypter.as_ref(), Path::new(&path))?
+            .to_string_lossy()
+            .to_string();
+        let pax = read_pax(&mut f).await?;
+        let result = async {
+            let mut w = HashWriter::new(SparseMap::from_records(&pax)?);
+            let (codec, _) = resolve_codec(
+                &pax,
+                &path,
+                &mut f,
+                &mut store,
+                target,
+                &compression,
+                crypter.as_ref(),
+            )
+            .await?;
+            codec.decode(&mut f, &mut w).await?;
+            w.finish()
+        }
+        .await;
+        let mut item = VerifyEntry {
+            path,
+            ..Default::default()
+        };
+        match result {
+            Ok((size, checksum)) => {
+                item.size = size;
+                item.checksum = checksum;
+            }
+            Err(err) => item.error = Some(err.to_string()),
+        }
+        items.push(item);
+    }
+    // the file may be appended more than once, only the last one is
+    // compared with the manifest
+    let mut compared = HashSet::new();
+    for item in items.iter_mut().rev() {
+        if !compared.insert(item.path.clone()) {
+            continue;
+        }
+        let Some(record) = manifest
+            .as_ref()
+            .and_then(|manifest| manifest.file(&item.path))
+        else {
+            continue;
+        };
+        item.expected = Some(record.checksum.clone());
+        if item.error.is_some() {
+            continue;
+        }
+        if record.size != item.size {
+            item.error = Some(format!(
+                "size mismatch, expected {} but {}",
+                record.size, item.size
+            ));
+        } else if record.checksum != item.checksum {
+            item.error = Some(format!(
+                "checksum mismatch, expected {} but {}",
+                record.checksum, item.checksum
+            ));
+        }
+    }
+    if let Some(manifest) = manifest {
+        for record in manifest.files {
+            if !compared.contains(&record.path) {
+                items.push(VerifyEntry {
+                    path: record.path,
+                    expected: Some(record.checksum),
+                    error: Some("file is missing".to_string()),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    Ok(items)
+}
+
+/// Append an entry whose data is compressed straight into the archive,
+/// the header is written with zero size first and rewritten when
+/// the compressed size is known, so the archive file must be seekable.
+async fn append_stream(
+    a: &mut Builder<File>,
+    header: &mut Header,
+    path: &Path,
+    codec: &dyn Codec,
+    reader: &mut Reader<'_>,
+    level: i32,
+) -> Result<u64, Error> {
+    header.set_size(0);
+    a.append_data(header, path, tokio::io::empty()).await?;
+    let w = a.get_mut();
+    w.flush().await?;
+    // the entry has no data, so its header is the last block
+    let offset = w.stream_position().await? - BLOCK_SIZE;
+    let size = codec.encode(reader, w, level).await?;
+    let remaining = (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE;
+    w.write_all(&[0; BLOCK_SIZE as usize][..remaining as usize])
+        .await?;
+    w.flush().await?;
+    // the end of file is not the end of entries when appending
+    let end = w.stream_position().await?;
+
+    header.set_size(size);
+    header.set_cksum();
+    w.seek(SeekFrom::Start(offset)).await?;
+    w.write_all(header.as_bytes()).await?;
+    w.flush().await?;
+    w.seek(SeekFrom::Start(end)).await?;
+    Ok(size)
+}
+
+/// Reader of the source file, only the data regions are read for sparse file.
+enum SourceReader {
+    File(HashReader<File>),
+    Sparse(SparseReader),
+}
+
+impl SourceReader {
+    async fn open(path: &Path, sparse: Option<SparseMap>) -> Result<Self, Error> {
+        Ok(match sparse {
+            Some(map) => SourceReader::Sparse(SparseReader::new(std::fs::File::open(path)?, map)),
+            None => SourceReader::File(HashReader::new(File::open(path).await?)),
+        })
+    }
+    fn size(&self) -> u64 {
+        match self {
+            SourceReader::File(r) => r.size(),
+            SourceReader::Sparse(r) => r.size(),
+        }
+    }
+    fn checksum(&self) -> String {
+        match self {
+            SourceReader::File(r) => r.checksum(),
+            SourceReader::Sparse(r) => r.checksum(),
+        }
+    }
+}
+
+impl AsyncRead for SourceReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            SourceReader::File(r) => Pin::new(r).poll_read(cx, buf),
+            SourceReader::Sparse(r) => Pin::new(r).poll_read(cx, buf),
+        }
+    }
+}
+
+/// Detect the sparse map of the source file if sparse is enabled.
+fn source_sparse_map(
+    entry: &SourceEntry,
+    params: &ArchiveParams,
+) -> Result<Option<SparseMap>, Error> {
+    if !params.sparse || !matches!(entry.kind, SourceKind::File) {
+        return Ok(None);
+    }
+    sparse_map(&std::fs::File::open(&entry.path)?)
+}
+
+fn manifest_file(
+    filename: &Path,
+    header: &Header,
+    meta: &std::fs::Metadata,
+    r: &SourceReader,
+) -> Result<ManifestFile, Error> {
+    Ok(ManifestFile {
+        path: filename.to_string_lossy().to_string(),
+        size: r.size(),
+        mode: header.mode()?,
+        checksum: r.checksum(),
+        mtime: Some(manifest_mtime(meta)),
+    })
+}
+
+/// Compress the file to a temp file, returns the tar header
+/// and the manifest record of the file.
+async fn compress_file(
+    codec: Arc<dyn Codec>,
+    file_path: PathBuf,
+    filename: PathBuf,
+    file: PathBuf,
+    level: i32,
+    sparse: Option<SparseMap>,
+) -> Result<(Header, ManifestFile), Error> {
+    let mut header = Header::new_gnu();
+    let meta = fs::metadata(&file_path).await?;
+    header.set_metadata(&meta);
+    let mut r = SourceReader::open(&file_path, sparse).await?;
+    let mut w = File::create(&file).await?;
+    let size = codec.encode(&mut r, &mut w, level).await?;
+    w.flush().await?;
+    header.set_size(size);
+    let record = manifest_file(&filename, &header, &meta, &r)?;
+    Ok((header, record))
+}
+
+/// Kind of the source entry, symlinks are archived as links
+/// instead of being followed.
+pub(crate) enum SourceKind {
+    File,
+    Dir,
+    Symlink(PathBuf),
+    /// Hard link to the first archived path of the same inode
+    HardLink(PathBuf),
+}
+
+pub(crate) struct SourceEntry {
+    pub(crate) path: PathBuf,
+    pub(crate) name: PathBuf,
+    pub(crate) kind: SourceKind,
+    pub(crate) meta: std::fs::Metadata,
+}
+
+#[cfg(unix)]
+fn inode(meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (meta.nlink() > 1).then(|| (meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode(_meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Collect the entries of source matched by the pattern. Glob follows
+/// symlinked directories, so the paths under them are skipped.
+fn collect_entries(source: &str, pattern: &str) -> Result<Vec<SourceEntry>, Error> {
+    let mut entries = vec![];
+    let mut symlinks: HashMap<PathBuf, bool> = HashMap::new();
+    let mut inodes: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    let mut under_symlink = |name: &Path| -> bool {
+        name.ancestors().skip(1).any(|dir| {
+            if dir.as_os_str().is_empty() {
+                return false;
+            }
+            *symlinks.entry(dir.to_path_buf()).or_insert_with(|| {
+                Path::new(source)
+                    .join(dir)
+                    .symlink_metadata()
+                    .map(|meta| meta.file_type().is_symlink())
+                    .unwrap_or_default()
+            })
+        })
+    };
+    for entry in
+        glob(&format!("{source}{pattern}")).map_err(|err| Error::Pattern { source: err })?
+    {
+        let file_path = match entry {
+            Ok(file_path) => file_path,
+            Err(err) => {
+                if let Ok(name) = err.path().strip_prefix(source)
+                    && under_symlink(name)
+                {
+                    continue;
+                }
+                return Err(Error::Glob { source: err });
+            }
+        };
+        let name = file_path
+            .strip_prefix(source)
+            .map_err(|err| Error::StripPrefix { source: err })?
+            .to_path_buf();
+        if under_symlink(&name) {
+            continue;
+        }
+        // the entries of these paths are skipped by unarchive
+        let path = name.to_string_lossy();
+        if is_metadata(&path) || is_chunk(&path) || path == CHUNK_DIR.trim_end_matches('/') {
+            return Err(Error::UnsafePath {
+                path: path.to_string(),
+                reason: "path is reserved by archiver".to_string(),
+            });
+        }
+        let meta = file_path.symlink_metadata()?;
+        let kind = if meta.file_type().is_symlink() {
+            SourceKind::Symlink(std::fs::read_link(&file_path)?)
+        } else if meta.is_dir() {
+            SourceKind::Dir
+        } else if let Some(key) = inode(&meta) {
+            match inodes.get(&key) {
+                Some(first) => SourceKind::HardLink(first.clone()),
+                None => {
+                    inodes.insert(key, name.clone());
+                    SourceKind::File
+                }
+            }
+        } else {
+            SourceKind::File
+        };
+        entries.push(SourceEntry {
+            path: file_path,
+            name,
+            kind,
+            meta,
+        });
+    }
+    Ok(entries)
+}
+
+/// Header of the entry without data, returns it with the link name.
+fn entry_header(
+    meta: &std::fs::Metadata,
+    kind: SourceKind,
+    crypter: Option<&Crypter>,
+) -> Result<(Header, Option<PathBuf>), Error> {
+    let mut header = Header::new_gnu();
+    header.set_metadata(meta);
+    let link = match kind {
+        SourceKind::Symlink(link) => Some(link),
+        SourceKind::HardLink(link) => {
+            header.set_entry_type(EntryType::Link);
+            Some(link)
+        }
+        _ => None,
+    };
+    header.set_size(0);
+    let link = link.map(|link| encrypt_name(crypter, &link)).transpose()?;
+    Ok((header, link))
+}
+
+/// PAX records of the entry, the mtime is recorded only if it has
+/// sub-second part, which can't be stored in the tar header.
+fn pax_records(
+    entry: &SourceEntry,
+    params: &ArchiveParams,
+    sparse: Option<&SparseMap>,
+) -> PaxRecords {
+    let meta = &entry.meta;
+    let mut records = if params.xattrs || params.acls {
+        read_xattrs(&entry.path, params.xattrs, params.acls)
+    } else {
+        PaxRecords::new()
+    };
+    let mtime = FileTime::from_last_modification_time(meta);
+    if mtime.nanoseconds() != 0 {
+        records.insert(PAX_MTIME.to_string(), format_time(mtime));
+    }
+    if params.atime {
+        let atime = FileTime::from_last_access_time(meta);
+        records.insert(PAX_ATIME.to_string(), format_time(atime));
+    }
+    if let Some(map) = sparse {
+        map.to_records(&mut records);
+    }
+    records
+}
+
+/// Append the entry without data, the GNU long link entry is
+/// appended first if the link name is too long for the header.
+async fn append_entry(
+    a: &mut Builder<File>,
+    mut header: Header,
+    path: &Path,
+    link: Option<&Path>,
+) -> Result<(), Error> {
+    if let Some(link) = link
+        && header.set_link_name(link).is_err()
+    {
+        let data = link.as_os_str().as_encoded_bytes();
+        let mut long = Header::new_gnu();
+        long.set_path("././@LongLink")?;
+        long.set_entry_type(EntryType::GNULongLink);
+        long.set_mode(0o644);
+        long.set_size(data.len() as u64 + 1);
+        long.set_cksum();
+        a.append(&long, data.chain(&[0][..])).await?;
+        let linkname = &mut header.as_old_mut().linkname;
+        let size = linkname.len().min(data.len());
+        linkname[..size].copy_from_slice(&data[..size]);
+    }
+    a.append_data(&mut header, path, tokio::io::empty()).await?;
+    Ok(())
+}
+
+enum Pending {
+    Compress(PathBuf, JoinHandle<Result<(Header, ManifestFile), Error>>),
+    Entry(Box<Header>, Option<PathBuf>),
+}
+
+async fn append_pending(
+    a: &mut Builder<File>,
+    filename: &Path,
+    records: &PaxRecords,
+    job: Pending,
+    manifest: &mut Manifest,
+    index: &mut ArchiveIndex,
+    compression: &Compression,
+) -> Result<(), Error> {
+    let offset = stream_position(a).await?;
+    append_pax(a, records).await?;
+    let size = match job {
+        Pending::Compress(file, handle) => {
+            let (header, record) = handle.await??;
+            let size = header.size()?;
+            append_compressed(a, filename, &file, header).await?;
+            manifest.files.push(record);
+            size
+        }
+        Pending::Entry(header, link) => {
+            append_entry(a, *header, filename, link.as_deref()).await?;
+            0
+        }
+    };
+    index_entry(a, index, filename, offset, size, compression).await
+}
+
+/// Append the manifest entry filled with spaces, returns the offset and size
+/// of its data. The data is rewritten when all files are appended.
+async fn reserve_manifest(a: &mut Builder<File>, size: u64) -> Result<(u64, u64), Error> {
+    let w = a.get_mut();
+    w.flush().await?;
+    let offset = w.stream_position().await? + BLOCK_SIZE;
+    let mut header = Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    a.append_data(
+        &mut header,
+        MANIFEST_PATH,
+        tokio::io::repeat(b' ').take(size),
+    )
+    .await?;
+    Ok((offset, size))
+}
+
+/// Rewrite the data of the reserved manifest entry, it fails if the json
+/// is larger than the reserved size, as it would overwrite the next entry.
+async fn write_manifest(
+    a: &mut Builder<File>,
+    (offset, size): (u64, u64),
+    manifest: &Manifest,
+) -> Result<(), Error> {
+    let data = manifest.to_json()?;
+    if data.len() as u64 > size {
+        return Err(std::io::Error::other(format!(
+            "manifest size {} exceeds the reserved size {size}",
+            data.len()
+        ))
+        .into());
+    }
+    let w = a.get_mut();
+    w.flush().await?;
+    w.seek(SeekFrom::Start(offset)).await?;
+    w.write_all(&data).await?;
+    w.flush().await?;
+    w.seek(SeekFrom::End(0)).await?;
+    Ok(())
+}
+
+/// Append the manifest entry with its data, returns the offset of its header.
+async fn append_manifest(a: &mut Builder<File>, manifest: &Manifest) -> Result<u64, Error> {
+    let offset = stream_position(a).await?;
+    let data = manifest.to_json()?;
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    a.append_data(&mut header, MANIFEST_PATH, &data[..]).await?;
+    Ok(offset)
+}
+
+/// Existing archive which the files are appended to.
+struct Appending {
+    /// Offset of the end of entries, the new entries are written from it
+    offset: u64,
+    /// Original data from the offset, it is the index and end of archive
+    tail: Vec<u8>,
+}
+
+impl Appending {
+    /// Path of the recovery marker, it records the offset and tail
+    /// while the files are appended.
+    fn marker(target: &str) -> PathBuf {
+        PathBuf::from(format!("{target}.appending"))
+    }
+
+    /// Write the recovery marker before the archive is modified,
+    /// so an append interrupted by a crash can be rolled back.
+    async fn save(&self, target: &str) -> Result<(), Error> {
+        let marker = Self::marker(target);
+        let temp = TempPath::try_from_path(temp_path(&marker))?;
+        let mut file = File::create(&temp).await?;
+        file.write_all(&self.offset.to_le_bytes()).await?;
+        file.write_all(&self.tail).await?;
+        file.sync_all().await?;
+        drop(file);
+        temp.persist(&marker).map_err(|err| err.error)?;
+        Ok(())
+    }
+
+    /// Remove the recovery marker after the append is done or restored.
+    async fn finish(target: &str) -> Result<(), Error> {
+        fs::remove_file(Self::marker(target)).await?;
+        Ok(())
+    }
+
+    /// Truncate the appended data and restore the original tail.
+    async fn restore(&self, target: &str) -> Result<(), Error> {
+        let mut file = fs::OpenOptions::new().write(true).open(target).await?;
+        file.set_len(self.offset).await?;
+        file.seek(SeekFrom::Start(self.offset)).await?;
+        file.write_all(&self.tail).await?;
+        file.sync_all().await?;
+        Ok(())
+    }
+}
+
+/// Lock the archive exclusively while it is appended or rewritten, so the
+/// other writers fail instead of modifying it at the same time.
+/// The lock is released when the returned file is dropped.
+fn lock_archive(target: &str) -> Result<std::fs::File, Error> {
+    let file = std::fs::File::open(target)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) => {
+            return Err(Error::AppendInProgress {
+                path: target.to_string(),
+            });
+        }
+        Err(std::fs::TryLockError::Error(err)) => return Err(err.into()),
+    }
+    // the archive may be replaced by update or delete before it is locked
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let locked = file.metadata()?;
+        let current = std::fs::metadata(target)?;
+        if (locked.dev(), locked.ino()) != (current.dev(), current.ino()) {
+            return Err(Error::AppendInProgress {
+                path: target.to_string(),
+            });
+        }
+    }
+    Ok(file)
+}
+
+/// Fail if the archive is being appended, or its append was interrupted and
+/// is not rolled back yet, the entries after the original end are incomplete.
+pub(crate) fn check_appending(target: &str) -> Result<(), Error> {
+    if Appending::marker(target).exists() {
+        return Err(Error::AppendInProgress {
+            path: target.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Roll back the append which was interrupted by a crash, the original
+/// end of the archive is restored from the recovery marker.
+/// The archive must be locked, returns true if it is rolled back.
+async fn recover_append(target: &str) -> Result<bool, Error> {
+    let data = match fs::read(Appending::marker(target)).await {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    // the marker is renamed after it is synced, so it is never partial
+    let Some((offset, tail)) = data.split_first_chunk::<8>() else {
+        return Err(std::io::Error::other("append recovery marker is invalid").into());
+    };
+    let appending = Appending {
+        offset: u64::from_le_bytes(*offset),
+        tail: tail.to_vec(),
+    };
+    appending.restore(target).await?;
+    Appending::finish(target).await?;
+    warn!(file = target, "interrupted append is rolled back");
+    Ok(true)
+}
+
+/// Roll back the append of the archive which was interrupted by a crash,
+/// it is done by the next append, update and delete too.
+/// Returns true if the archive is rolled back.
+pub async fn repair(target: &str) -> Result<bool, Error> {
+    let _lock = lock_archive(target)?;
+    recover_append(target).await
+}
+
+/// Open the existing archive to append files, the codec must be the same
+/// as the archive, and the key is required if the archive is encrypted.
+async fn open_append(
+    target: &str,
+    params: &ArchiveParams,
+    compression: &Compression,
+) -> Result<(Appending, Manifest, Option<Crypter>, ArchiveIndex), Error> {
+    let mut manifest = read_manifest(target).await?;
+    resolve_compression(target, Some(compression.clone()), manifest.as_ref())?;
+    // the new entries overwrite the index, or the end of archive without index.
+    // The manifest appended last time is right before the index, it is overwritten too.
+    let (index, offset) = match (read_index(target).await?, index_offset(target)?) {
+        (Some(index), Some(offset)) => {
+            let offset = index.manifest.unwrap_or(offset);
+            (index, offset)
+        }
+        _ => scan_index(target, compression).await?,
+    };
+    index.check_codec(compression)?;
+    let key = params.encryption.as_ref().map(|encryption| &encryption.key);
+    let crypter = open_manifest(manifest.as_mut(), key)?;
+    if crypter.is_none() && key.is_some() {
+        return Err(Error::InvalidKey {
+            message: "archive is not encrypted".to_string(),
+        });
+    }
+    let manifest = manifest.unwrap_or_else(|| Manifest {
+        version: MANIFEST_VERSION,
+        compression: compression.to_string(),
+        level: params.level,
+        ..Default::default()
+    });
+    let mut file = File::open(target).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut tail = vec![];
+    file.read_to_end(&mut tail).await?;
+    Ok((Appending { offset, tail }, manifest, crypter, index))
+}
+
+/// Append the entry of directory or link, and record it in the index.
+async fn append_link(
+    a: &mut Builder<File>,
+    index: &mut ArchiveIndex,
+    entry: SourceEntry,
+    name: &Path,
+    offset: u64,
+    crypter: Option<&Crypter>,
+    compression: &Compression,
+) -> Result<(), Error> {
+    let (header, link) = entry_header(&entry.meta, entry.kind, crypter)?;
+    append_entry(a, header, name, link.as_deref()).await?;
+    index_entry(a, index, name, offset, 0, compression).await
+}
+
+/// Existing archive which is rewritten, the kept entries are copied
+/// verbatim without recompressing.
+struct Rewrite {
+    /// Manifest with the records of kept files, it is not sealed
+    manifest: Manifest,
+    crypter: Option<Crypter>,
+    entries: Vec<IndexEntry>,
+}
+
+/// Copy the raw entries of archive verbatim, including their PAX
+/// and GNU long name headers, and record them in the index.
+async fn copy_entries(
+    a: &mut Builder<File>,
+    source: &str,
+    entries: &[IndexEntry],
+    index: &mut ArchiveIndex,
+) -> Result<(), Error> {
+    let mut file = File::open(source).await?;
+    for entry in entries {
+        let header = stream_position(a).await?;
+        let size = entry.offset + entry.length.div_ceil(BLOCK_SIZE) * BLOCK_SIZE - entry.header;
+        file.seek(SeekFrom::Start(entry.header)).await?;
+        let copied = tokio::io::copy(&mut (&mut file).take(size), a.get_mut()).await?;
+        if copied != size {
+            return Err(Error::InvalidIndex {
+                message: format!("entry {} is truncated", entry.path),
+            });
+        }
+        index.entries.push(IndexEntry {
+            header,
+            offset: header + entry.offset - entry.header,
+            ..entry.clone()
+        });
+    }
+    Ok(())
+}
+
+/// Open the archive to rewrite, returns its compression, manifest and crypter,
+/// with the latest entry of each path by its decrypted path.
+async fn open_rewrite(
+    target: &str,
+    compression: Option<Compression>,
+    key: Option<&KeySource>,
+    level: i32,
+) -> Result<
+    (
+        Compression,
+        Manifest,
+        Option<Crypter>,
+        Vec<(String, IndexEntry)>,
+    ),
+    Error,
+> {
+    let mut manifest = read_manifest(target).await?;
+    let compression = resolve_compression(target, compression, manifest.as_ref())?;
+    let index = match read_index(target).await? {
+        Some(index) => index,
+        None => scan_index(target, &compression).await?.0,
+    };
+    index.check_codec(&compression)?;
+    let crypter = open_manifest(manifest.as_mut(), key)?;
+    if crypter.is_none() && key.is_some() {
+        return Err(Error::InvalidKey {
+            message: "archive is not encrypted".to_string(),
+        });
+    }
+    let manifest = manifest.unwrap_or_else(|| Manifest {
+        version: MANIFEST_VERSION,
+        compression: compression.to_string(),
+        level,
+        ..Default::default()
+    });
+    // the entries replaced by appending are dropped
+    let mut paths = HashSet::new();
+    let mut entries = vec![];
+    for entry in index.entries.into_iter().rev() {
+        let path = decrypt_name(crypter.as_ref(), Path::new(&entry.path))?
+            .to_string_lossy()
+            .to_string();
+        if paths.insert(path.clone()) {
+            entries.push((path, entry));
+        }
+    }
+    entries.reverse();
+    Ok((compression, manifest, crypter, entries))
+}
+
+/// The archived entry is unchanged if its type and mtime are the same as
+/// the source, and the size of file is the same as the manifest record.
+async fn is_unchanged(
+    target: &str,
+    archived: &IndexEntry,
+    entry: &SourceEntry,
+    size: Option<u64>,
+) -> Result<bool, Error> {
+    let mut r = open_archive_at(target, archived.header)?;
+    let mut entries = r.entries()?;
+    let Some(file) = entries.next().await else {
+        return Ok(false);
+    };
+    let mut f = file?;
+    let pax = read_pax(&mut f).await?;
+    let header = f.header();
+    let mtime = pax
+        .get(PAX_MTIME)
+        .and_then(|value| parse_time(value))
+        .or_else(|| {
+            let mtime = header.mtime().ok()?;
+            Some(FileTime::from_unix_time(mtime as i64, 0))
+        });
+    if mtime != Some(FileTime::from_last_modification_time(&entry.meta)) {
+        return Ok(false);
+    }
+    let entry_type = header.entry_type();
+    Ok(match entry.kind {
+        SourceKind::File => {
+            entry_type.is_file() && size.is_none_or(|size| size == entry.meta.len())
+        }
+        SourceKind::Dir => entry_type.is_dir(),
+        SourceKind::Symlink(_) => entry_type.is_symlink(),
+        SourceKind::HardLink(_) => entry_type.is_hard_link(),
+    })
+}
+
+/// Update the archive with the files of source, the changed and new files are
+/// compressed and appended, the other entries are copied verbatim. The entries
+/// of removed files are kept, and the archive is created if it does not exist.
+/// The chunks which only the replaced files refer to are dropped, and an
+/// interrupted append is rolled back first.
+pub async fn update(params: ArchiveParams) -> Result<(), Error> {
+    if !Path::new(&params.target).exists() {
+        return archive(params).await;
+    }
+    if !Path::new(&params.source).exists() {
+        return Err(Error::PathNotExists {
+            path: params.source,
+        });
+    }
+    let _lock = lock_archive(&params.target)?;
+    recover_append(&params.target).await?;
+    let key = params.encryption.as_ref().map(|encryption| &encryption.key);
+    let (compression, mut manifest, crypter, archived) = open_rewrite(
+        &params.target,
+        params.compression.clone(),
+        key,
+        params.level,
+    )
+    .await?;
+    let mut changed = HashSet::new();
+    let mut entries = vec![];
+    {
+        let sizes: HashMap<&str, u64> = manifest
+            .files
+            .iter()
+            .map(|file| (file.path.as_str(), file.size))
+            .collect();
+        let archived: HashMap<&str, &IndexEntry> = archived
+            .iter()
+            .map(|(path, entry)| (path.as_str(), entry))
+            .collect();
+        for entry in collect_entries(&params.source, &params.pattern)? {
+            let path = entry.name.to_string_lossy().to_string();
+            let unchanged = match archived.get(path.as_str()) {
+                // the hard link is appended after its changed target
+                Some(_)
+                    if matches!(&entry.kind, SourceKind::HardLink(first)
+                        if changed.contains(&first.to_string_lossy().to_string())) =>
+                {
+                    false
+                }
+                Some(archived) => {
+                    is_unchanged(
+                        &params.target,
+                        archived,
+                        &entry,
+                        sizes.get(path.as_str()).copied(),
+                    )
+                    .await?
+                }
+                None => false,
+            };
+            if !unchanged {
+                changed.insert(path);
+                entries.push(entry);
+            }
+        }
+    }
+    if entries.is_empty() {
+        info!(file = params.target, "archive is up to date");
+        return Ok(());
+    }
+    manifest.files.retain(|file| !changed.contains(&file.path));
+    let kept = archived
+        .into_iter()
+        .filter(|(path, _)| !changed.contains(path))
+        .collect();
+    let kept = drop_unused_chunks(&params.target, kept, &compression, crypter.as_ref()).await?;
+    write_archive(
+        ArchiveParams {
+            compression: Some(compression),
+            encryption: None,
+            ..params
+        },
+        entries,
+        Some(Rewrite {
+            manifest,
+            crypter,
+            entries: kept,
+        }),
+        None,
+    )
+    .await
+}
+
+/// The path is the same as the parent or under it.
+fn is_under(path: &str, parent: &str) -> bool {
+    let path = path.trim_end_matches('/');
+    let parent = parent.trim_end_matches('/');
+    path.strip_prefix(parent)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Delete the entries of paths from the archive, including the entries under
+/// the directories. The other entries are copied verbatim except the chunks
+/// which only the deleted files refer to, and an interrupted append is rolled
+/// back first. Returns the count of deleted entries.
+pub async fn delete(
+    target: &str,
+    paths: &[String],
+    compression: Option<Compression>,
+    key: Option<&KeySource>,
+) -> Result<usize, Error> {
+    if paths.is_empty() {
+        return Err(Error::InvalidArg {
+            path: String::new(),
+        });
+    }
+    let _lock = lock_archive(target)?;
+    recover_append(target).await?;
+    let (compression, mut manifest, crypter, archived) =
+        open_rewrite(target, compression, key, 0).await?;
+    if let Some(path) = paths
+        .iter()
+        .find(|path| !archived.iter().any(|(name, _)| is_under(name, path)))
+    {
+        return Err(Error::PathNotExists { path: path.clone() });
+    }
+    let deleted = |name: &str| paths.iter().any(|path| is_under(name, path));
+    let (removed, kept): (Vec<_>, Vec<_>) =
+        archived.into_iter().partition(|(name, _)| deleted(name));
+    manifest.files.retain(|file| !deleted(&file.path));
+    let kept = drop_unused_chunks(target, kept, &compression, crypter.as_ref()).await?;
+    let level = manifest.level;
+    write_archive(
+        ArchiveParams {
+            target: target.to_string(),
+            level,
+            compression: Some(compression),
+            ..Default::default()
+        },
+        vec![],
+        Some(Rewrite {
+            manifest,
+            crypter,
+            entries: kept,
+        }),
+        None,
+    )
+    .await?;
+    Ok(removed.len())
+}
+
+async fn append_compressed(
+    a: &mut Builder<File>,
+    filename: &Path,
+    file: &Path,
+    mut header: Header,
+) -> Result<(), Error> {
+    a.append_data(&mut header, filename, File::open(file).await?)
+        .await?;
+    fs::remove_file(file).await?;
+    debug!(
+        file = filename.to_string_lossy().to_string(),
+        size = bytesize::ByteSize(header.size()?).to_string(),
+        "encode done"
+    );
+    Ok(())
+}
+
 pub async fn archive(params: ArchiveParams) -> Result<(), Error> {
     if params.target.is_empty() {
         return Err(Error::InvalidArg {
@@ -180,93 +1788,312 @@ pub async fn archive(params: ArchiveParams) -> Result<(), Error> {
             path: params.source,
         });
     }
-    let dir = tempfile::tempdir()?;
-    let source = params.source;
-    let target = params.target;
-    let level = params.level;
-    if !Path::new(&source).exists() {
+    if !Path::new(&params.source).exists() {
         return Err(Error::PathNotExists {
-            path: source.clone(),
+            path: params.source,
         });
     }
+    let mut entries = collect_entries(&params.source, &params.pattern)?;
+    let mut incremental = None;
+    if let Some(base) = params.base.as_ref() {
+        if params.append {
+            return Err(Error::InvalidArg { path: base.clone() });
+        }
+        let key = params.encryption.as_ref().map(|encryption| &encryption.key);
+        let (changed, info) = changed_entries(entries, base, key).await?;
+        entries = changed;
+        incremental = Some(info);
+    }
+    write_archive(params, entries, None, incremental).await
+}
 
-    let filename = Path::new(&target)
-        .file_name()
-        .ok_or(Error::InvalidArg {
-            path: target.clone(),
-        })?
-        .to_string_lossy();
-    let arr: Vec<&str> = filename.split('.').collect();
-    if arr.len() < 3 || arr[2] != "tar" {
+/// Write the entries to the archive, the kept entries of the rewritten
+/// archive are copied before them.
+async fn write_archive(
+    params: ArchiveParams,
+    entries: Vec<SourceEntry>,
+    rewrite: Option<Rewrite>,
+    incremental: Option<Incremental>,
+) -> Result<(), Error> {
+    if params.dedup && (params.stream || params.sparse) {
         return Err(Error::InvalidArg {
-            path: target.clone(),
+            path: params.source,
         });
     }
-    let compress_type = arr[1];
+    let dir = tempfile::tempdir()?;
+    let target = params.target.clone();
+    let level = params.level;
+
+    let compression = match params.compression.clone() {
+        Some(compression) => compression,
+        None => Compression::from_path(&target)?,
+    };
 
-    let file = File::create(&target).await?;
-    let mut a = Builder::new(file);
     let mut file_count = 0;
     let start = SystemTime::now();
     let mut total_size = 0;
 
-    for entry in glob(&format!("{source}{}", params.pattern))
-        .map_err(|err| Error::Pattern { source: err })?
-    {
-        let file_path = entry.map_err(|err| Error::Glob { source: err })?;
-        let filename = file_path
-            .strip_prefix(&source)
-            .map_err(|err| Error::StripPrefix { source: err })?;
-        if file_path.is_dir() {
-            continue;
+    for entry in entries.iter() {
+        if let SourceKind::File = entry.kind {
+            total_size += entry.meta.len();
         }
-        if let Ok(meta) = file_path.metadata() {
-            total_size += meta.len();
-        }
-
-        let file = dir.path().join(uuid());
-        debug!(
-            file = filename.to_string_lossy().to_string(),
-            "start to encode"
-        );
+    }
 
-        let size = match compress_type {
-            GZIP => compression::gzip_encode(&file_path, &file, level).await,
-            ZSTD => compression::zstd_encode(&file_path, &file, level).await,
-            BROTLI => compression::brotli_encode(&file_path, &file, level).await,
-            SNAPPY => compression::snappy_encode(&file_path, &file).await,
-            LZ4 => compression::lz4_encode(&file_path, &file).await,
-            DEFLATE => compression::deflate_encode(&file_path, &file, level).await,
-            XZ => compression::xz_encode(&file_path, &file, level).await,
-            _ => Err(Error::InvalidCompression {
-                compression: compress_type.to_string(),
-            }),
-        }?;
-        debug!(
-            file = filename.to_string_lossy().to_string(),
-            size = bytesize::ByteSize(size as u64).to_string(),
-            "encode done"
-        );
-        a.append_file(filename, &mut File::open(&file).await?)
+    // the archive is locked until the append is done or restored,
+    // the rewrite of update and delete is locked by the caller
+    let lock = if rewrite.is_none() && params.append && Path::new(&target).exists() {
+        let lock = lock_archive(&target)?;
+        recover_append(&target).await?;
+        Some(lock)
+    } else {
+        None
+    };
+    let mut temp = None;
+    let mut appending = None;
+    let mut copies = vec![];
+    let (file, mut manifest, crypter, mut index) = if let Some(rewrite) = rewrite {
+        let path = TempPath::try_from_path(temp_path(Path::new(&target)))?;
+        let file = File::create(&path).await?;
+        temp = Some(path);
+        copies = rewrite.entries;
+        let index = ArchiveIndex {
+            version: INDEX_VERSION,
+            ..Default::default()
+        };
+        (file, rewrite.manifest, rewrite.crypter, index)
+    } else if lock.is_some() {
+        let (append, manifest, crypter, index) =
+            open_append(&target, &params, &compression).await?;
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&target)
             .await?;
-        file_count += 1;
+        append.save(&target).await?;
+        file.seek(SeekFrom::Start(append.offset)).await?;
+        appending = Some(append);
+        (file, manifest, crypter, index)
+    } else {
+        // the archive is written to a temp file, which is removed if it fails
+        let path = TempPath::try_from_path(temp_path(Path::new(&target)))?;
+        let file = File::create(&path).await?;
+        temp = Some(path);
+        let crypter = match params.encryption.as_ref() {
+            Some(encryption) => Some(Crypter::create(encryption)?),
+            None => None,
+        };
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            compression: compression.to_string(),
+            level,
+            encryption: crypter.as_ref().map(|(_, info)| info.clone()),
+            incremental,
+            ..Default::default()
+        };
+        let index = ArchiveIndex {
+            version: INDEX_VERSION,
+            ..Default::default()
+        };
+        (file, manifest, crypter.map(|(crypter, _)| crypter), index)
+    };
+    let mut a = Builder::new(file);
+    // the manifest of new archive is reserved as the first entry
+    let reserved = if appending.is_none() {
+        let paths: Vec<String> = entries
+            .iter()
+            .filter(|entry| matches!(entry.kind, SourceKind::File))
+            .map(|entry| entry.name.to_string_lossy().to_string())
+            .chain(manifest.files.iter().map(|file| file.path.clone()))
+            .collect();
+        Some(reserve_manifest(&mut a, manifest.reserved_size(&paths)?).await?)
+    } else {
+        None
+    };
+    if !copies.is_empty() {
+        copy_entries(&mut a, &target, &copies, &mut index).await?;
+        file_count += copies.len();
+    }
+
+    let result: Result<(), Error> = async {
+        if params.dedup {
+            let mut w = ChunkWriter::new(crypter.clone(), compression.clone(), level);
+            for entry in entries {
+                let records = pax_records(&entry, &params, None);
+                let name = encrypt_name(crypter.as_ref(), &entry.name)?;
+                if matches!(entry.kind, SourceKind::File) {
+                    let record = w.append(&mut a, &mut index, &entry, &name, records).await?;
+                    manifest.files.push(record);
+                } else {
+                    let offset = stream_position(&mut a).await?;
+                    append_pax(&mut a, &records).await?;
+                    append_link(
+                        &mut a,
+                        &mut index,
+                        entry,
+                        &name,
+                        offset,
+                        crypter.as_ref(),
+                        &compression,
+                    )
+                    .await?;
+                }
+                file_count += 1;
+            }
+        } else if params.stream {
+            for entry in entries {
+                let sparse = source_sparse_map(&entry, &params)?;
+                let records = pax_records(&entry, &params, sparse.as_ref());
+                let filename = entry.name.clone();
+                let name = encrypt_name(crypter.as_ref(), &filename)?;
+                let offset = stream_position(&mut a).await?;
+                append_pax(&mut a, &records).await?;
+                if !matches!(entry.kind, SourceKind::File) {
+                    append_link(
+                        &mut a,
+                        &mut index,
+                        entry,
+                        &name,
+                        offset,
+                        crypter.as_ref(),
+                        &compression,
+                    )
+                    .await?;
+                    file_count += 1;
+                    continue;
+                }
+                debug!(
+                    file = filename.to_string_lossy().to_string(),
+                    "start to encode"
+                );
+                let mut header = Header::new_gnu();
+                header.set_metadata(&entry.meta);
+                let mut r = SourceReader::open(&entry.path, sparse).await?;
+                let codec =
+                    entry_codec(&compression, crypter.as_ref(), &filename.to_string_lossy());
+                let size = append_stream(&mut a, &mut header, &name, codec.as_ref(), &mut r, level)
+                    .await?;
+                index_entry(&mut a, &mut index, &name, offset, size, &compression).await?;
+                manifest
+                    .files
+                    .push(manifest_file(&filename, &header, &entry.meta, &r)?);
+                debug!(
+                    file = filename.to_string_lossy().to_string(),
+                    size = bytesize::ByteSize(size).to_string(),
+                    "encode done"
+                );
+                file_count += 1;
+            }
+        } else {
+            // files are compressed by workers concurrently,
+            // but appended in the order of the glob result
+            let jobs = params.jobs.max(1);
+            let mut pending = VecDeque::with_capacity(jobs);
+            for entry in entries {
+                let sparse = source_sparse_map(&entry, &params)?;
+                let records = pax_records(&entry, &params, sparse.as_ref());
+                let filename = entry.name;
+                let name = encrypt_name(crypter.as_ref(), &filename)?;
+                if !matches!(entry.kind, SourceKind::File) {
+                    let (header, link) = entry_header(&entry.meta, entry.kind, crypter.as_ref())?;
+                    pending.push_back((name, records, Pending::Entry(Box::new(header), link)));
+                } else {
+                    debug!(
+                        file = filename.to_string_lossy().to_string(),
+                        "start to encode"
+                    );
+                    let file = dir.path().join(uuid());
+                    let handle = tokio::spawn(compress_file(
+                        entry_codec(&compression, crypter.as_ref(), &filename.to_string_lossy()),
+                        entry.path,
+                        filename,
+                        file.clone(),
+                        level,
+                        sparse,
+                    ));
+                    pending.push_back((name, records, Pending::Compress(file, handle)));
+                }
+                if pending.len() >= jobs
+                    && let Some((filename, records, job)) = pending.pop_front()
+                {
+                    append_pending(
+                        &mut a,
+                        &filename,
+                        &records,
+                        job,
+                        &mut manifest,
+                        &mut index,
+                        &compression,
+                    )
+                    .await?;
+                    file_count += 1;
+                }
+            }
+            while let Some((filename, records, job)) = pending.pop_front() {
+                append_pending(
+                    &mut a,
+                    &filename,
+                    &records,
+                    job,
+                    &mut manifest,
+                    &mut index,
+                    &compression,
+                )
+                .await?;
+                file_count += 1;
+            }
+        }
+        manifest.retain_latest();
+        if let Some(crypter) = crypter.as_ref() {
+            manifest.seal(crypter)?;
+        }
+        match reserved {
+            Some(reserved) => write_manifest(&mut a, reserved, &manifest).await?,
+            None => index.manifest = Some(append_manifest(&mut a, &manifest).await?),
+        }
+        append_index(&mut a, &index).await?;
+        a.finish().await?;
+        Ok(())
+    }
+    .await;
+    if let Err(err) = result {
+        if let Some(appending) = appending {
+            // the pending data is written before it is truncated
+            let _ = a.get_mut().flush().await;
+            appending.restore(&target).await?;
+            Appending::finish(&target).await?;
+        }
+        return Err(err);
+    }
+    let mut file = a.into_inner().await?;
+    file.flush().await?;
+    if appending.is_some() {
+        // the original index may be longer than the appended data
+        let size = file.stream_position().await?;
+        file.set_len(size).await?;
+    }
+    file.sync_all().await?;
+    drop(file);
+    if appending.is_some() {
+        Appending::finish(&target).await?;
+    }
+    if let Some(temp) = temp {
+        temp.persist(&target).map_err(|err| err.error)?;
     }
-    a.finish().await?;
     let mut duration = None;
     if let Ok(d) = SystemTime::now().duration_since(start) {
         duration = Some(humantime::format_duration(d).to_string());
     };
     let mut file_size = None;
-    if let Ok(file) = File::open(&target).await {
-        if let Ok(meta) = file.metadata().await {
-            file_size = Some(bytesize::ByteSize(meta.len()).to_string());
-        }
+    if let Ok(file) = File::open(&target).await
+        && let Ok(meta) = file.metadata().await
+    {
+        file_size = Some(bytesize::ByteSize(meta.len()).to_string());
     }
     info!(
         file = target,
         file_size,
         total_size = bytesize::ByteSize(total_size).to_string(),
-        compression = compress_type,
+        compression = compression.to_string(),
         level,
         file_count,
         duration,
@@ -0,0 +1,228 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock, RwLock};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::compression;
+use super::error::Error;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+pub type Reader<'a> = dyn AsyncRead + Unpin + Send + 'a;
+pub type Writer<'a> = dyn AsyncWrite + Unpin + Send + 'a;
+
+/// Codec compresses and decompresses the data of each file as stream.
+pub trait Codec: Send + Sync {
+    /// Name of the codec, it is used as the extension of archive file,
+    /// e.g. `zst` for `name.zst.tar`.
+    fn name(&self) -> &str;
+    /// Encode data from reader to writer, returns the size of encoded data.
+    fn encode<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+        level: i32,
+    ) -> BoxFuture<'a, Result<u64, Error>>;
+    /// Decode data from reader to writer, returns the size of decoded data.
+    fn decode<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+    ) -> BoxFuture<'a, Result<u64, Error>>;
+    /// Decode data like `decode`, the codec which decodes in memory
+    /// should reject the data larger than `max_size` before allocating.
+    fn decode_limited<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+        _max_size: Option<u64>,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        self.decode(reader, writer)
+    }
+}
+
+static CODECS: LazyLock<RwLock<HashMap<String, Arc<dyn Codec>>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Register a custom codec, then it can be selected by its name
+/// for archive and unarchive. The names of built-in codecs can't be used,
+/// and the name can't contain `.` or `/` as it is the extension of archive.
+pub fn register_codec<C: Codec + 'static>(codec: C) -> Result<(), Error> {
+    let name = codec.name().to_string();
+    if name.is_empty() || name.contains(['.', '/', '\\']) || Compression::builtin(&name).is_some() {
+        return Err(Error::InvalidCompression { compression: name });
+    }
+    let mut codecs = CODECS
+        .write()
+        .map_err(|_| std::io::Error::other("codec registry is poisoned"))?;
+    codecs.insert(name, Arc::new(codec));
+    Ok(())
+}
+
+fn custom_codec(name: &str) -> Option<Arc<dyn Codec>> {
+    CODECS
+        .read()
+        .ok()
+        .and_then(|codecs| codecs.get(name).cloned())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Brotli,
+    Lz4,
+    Snappy,
+    Deflate,
+    Xz,
+    /// Codec registered by `register_codec`
+    Custom(String),
+}
+
+impl Compression {
+    fn builtin(value: &str) -> Option<Self> {
+        let compression = match value {
+            "gz" | "gzip" => Compression::Gzip,
+            "zst" | "zstd" => Compression::Zstd,
+            "br" | "brotli" => Compression::Brotli,
+            "lz4" => Compression::Lz4,
+            "sz" | "snappy" => Compression::Snappy,
+            "zip" | "deflate" => Compression::Deflate,
+            "xz" => Compression::Xz,
+            _ => return None,
+        };
+        Some(compression)
+    }
+    /// Extension of archive file, e.g. `gz` for `name.gz.tar`.
+    pub fn extension(&self) -> &str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+            Compression::Brotli => "br",
+            Compression::Lz4 => "lz4",
+            Compression::Snappy => "sz",
+            Compression::Deflate => "zip",
+            Compression::Xz => "xz",
+            Compression::Custom(name) => name,
+        }
+    }
+    /// Infer the compression from the archive file name `name.<codec>.tar`,
+    /// the name may contain dots, e.g. `backup.2025-01-01.zst.tar`.
+    pub fn from_path(path: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidArg {
+            path: path.to_string(),
+        };
+        let filename = Path::new(path)
+            .file_name()
+            .ok_or_else(invalid)?
+            .to_string_lossy();
+        let (name, extension) = filename
+            .strip_suffix(".tar")
+            .and_then(|value| value.rsplit_once('.'))
+            .ok_or_else(invalid)?;
+        if name.is_empty() {
+            return Err(invalid());
+        }
+        extension.parse()
+    }
+    fn custom(&self) -> Result<Arc<dyn Codec>, Error> {
+        custom_codec(self.extension()).ok_or(Error::InvalidCompression {
+            compression: self.to_string(),
+        })
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(compression) = Compression::builtin(value) {
+            return Ok(compression);
+        }
+        if custom_codec(value).is_some() {
+            return Ok(Compression::Custom(value.to_string()));
+        }
+        Err(Error::InvalidCompression {
+            compression: value.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+impl Codec for Compression {
+    fn name(&self) -> &str {
+        self.extension()
+    }
+    fn encode<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+        level: i32,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move {
+            match self {
+                Compression::Gzip => compression::gzip_encode(reader, writer, level).await,
+                Compression::Zstd => compression::zstd_encode(reader, writer, level).await,
+                Compression::Brotli => compression::brotli_encode(reader, writer, level).await,
+                Compression::Lz4 => compression::lz4_encode(reader, writer).await,
+                Compression::Snappy => compression::snappy_encode(reader, writer).await,
+                Compression::Deflate => compression::deflate_encode(reader, writer, level).await,
+                Compression::Xz => compression::xz_encode(reader, writer, level).await,
+                Compression::Custom(_) => self.custom()?.encode(reader, writer, level).await,
+            }
+        })
+    }
+    fn decode<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        self.decode_limited(reader, writer, None)
+    }
+    fn decode_limited<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+        max_size: Option<u64>,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move {
+            match self {
+                Compression::Gzip => compression::gzip_decode(reader, writer).await,
+                Compression::Zstd => compression::zstd_decode(reader, writer).await,
+                Compression::Brotli => compression::brotli_decode(reader, writer).await,
+                Compression::Lz4 => compression::lz4_decode_limited(reader, writer, max_size).await,
+                Compression::Snappy => {
+                    compression::snappy_decode_limited(reader, writer, max_size).await
+                }
+                Compression::Deflate => compression::deflate_decode(reader, writer).await,
+                Compression::Xz => compression::xz_decode(reader, writer).await,
+                Compression::Custom(_) => {
+                    self.custom()?
+                        .decode_limited(reader, writer, max_size)
+                        .await
+                }
+            }
+        })
+    }
+}
@@ -12,216 +12,268 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use async_compression::tokio::write::{
-    BrotliDecoder, BrotliEncoder, DeflateEncoder, GzipDecoder, GzipEncoder, XzDecoder, XzEncoder,
-    ZstdEncoder,
-};
 use async_compression::Level;
-use filetime::{set_file_mtime, FileTime};
-use lz4_flex::block::{compress_prepend_size, uncompressed_size};
-use std::path::PathBuf;
-use tokio::fs;
-use tokio::fs::File;
-use tokio::io::{copy, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio_tar::{Archive, Entry};
+use async_compression::tokio::bufread::{
+    BrotliDecoder, BrotliEncoder, DeflateDecoder, DeflateEncoder, GzipDecoder, GzipEncoder,
+    XzDecoder, XzEncoder, ZstdDecoder, ZstdEncoder,
+};
+use lz4_flex::block::decompress_size_prepended;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, copy};
 
 use super::error::Error;
+use super::frame;
 
-async fn write_file(target: &PathBuf, data: &[u8]) -> Result<usize, Error> {
-    let mut file = File::create(target).await?;
-    file.write_all(data).await?;
-    file.flush().await?;
-    Ok(data.len())
+/// Read the first bytes of the reader, returns them with the size of read data,
+/// so that the format can be detected before decoding.
+async fn peek<R, const N: usize>(reader: &mut R) -> Result<([u8; N], usize), Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut buf = [0; N];
+    let size = frame::read_full(reader, &mut buf).await?;
+    Ok((buf, size))
 }
 
-async fn write_file_and_mtime(
-    target: &PathBuf,
-    data: &[u8],
-    header: &tokio_tar::Header,
-) -> Result<usize, Error> {
-    if let Some(path) = target.parent() {
-        fs::create_dir_all(path).await?;
-    }
-    let size = write_file(target, data).await?;
-    if let Ok(mtime) = header.mtime() {
-        set_file_mtime(target, FileTime::from_unix_time(mtime as i64, 0))?;
-    }
+pub async fn gzip_encode<R, W>(reader: &mut R, writer: &mut W, level: i32) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut r = GzipEncoder::with_quality(BufReader::new(reader), Level::Precise(level));
+    let size = copy(&mut r, writer).await?;
     Ok(size)
 }
 
-async fn copy_mtime(file: &PathBuf, target: &PathBuf) -> Result<(), Error> {
-    let f = File::open(file).await?;
-    let meta = f.metadata().await?;
-    set_file_mtime(target, FileTime::from_last_modification_time(&meta))?;
-    Ok(())
+pub async fn gzip_decode<R, W>(reader: &mut R, writer: &mut W) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut r = GzipDecoder::new(BufReader::new(reader));
+    let size = copy(&mut r, writer).await?;
+    Ok(size)
 }
 
-async fn compress<W>(file: &PathBuf, writer: &mut W) -> Result<u64, Error>
+pub async fn zstd_encode<R, W>(reader: &mut R, writer: &mut W, level: i32) -> Result<u64, Error>
 where
+    R: AsyncRead + Unpin + ?Sized,
     W: AsyncWrite + Unpin + ?Sized,
 {
-    let mut r = File::open(file).await?;
+    let mut r = ZstdEncoder::with_quality(BufReader::new(reader), Level::Precise(level));
     let size = copy(&mut r, writer).await?;
     Ok(size)
 }
 
-pub async fn gzip_encode(file: &PathBuf, target: &PathBuf, level: i32) -> Result<usize, Error> {
-    let mut w = GzipEncoder::with_quality(Vec::new(), Level::Precise(level));
-    compress(file, &mut w).await?;
-    w.shutdown().await?;
-    let size = write_file(target, &w.into_inner()).await?;
-    copy_mtime(file, target).await?;
+pub async fn zstd_decode<R, W>(reader: &mut R, writer: &mut W) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut r = ZstdDecoder::new(BufReader::new(reader));
+    let size = copy(&mut r, writer).await?;
     Ok(size)
 }
 
-pub async fn gzip_decode(
-    file: &mut Entry<Archive<File>>,
-    target: &Option<PathBuf>,
-) -> Result<Vec<u8>, Error> {
-    let mut w = GzipDecoder::new(Vec::new());
-    let _ = copy(file, &mut w).await?;
-    w.shutdown().await?;
-    let buf = w.into_inner();
-    if let Some(target) = target {
-        write_file_and_mtime(target, &buf, file.header()).await?;
-    }
-    Ok(buf)
+pub async fn brotli_encode<R, W>(reader: &mut R, writer: &mut W, level: i32) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut r = BrotliEncoder::with_quality(BufReader::new(reader), Level::Precise(level));
+    let size = copy(&mut r, writer).await?;
+    Ok(size)
 }
 
-pub async fn zstd_encode(file: &PathBuf, target: &PathBuf, level: i32) -> Result<usize, Error> {
-    let mut w = ZstdEncoder::with_quality(Vec::new(), Level::Precise(level));
-    compress(file, &mut w).await?;
-    w.shutdown().await?;
-    let size = write_file(target, &w.into_inner()).await?;
-    copy_mtime(file, target).await?;
+pub async fn brotli_decode<R, W>(reader: &mut R, writer: &mut W) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut r = BrotliDecoder::new(BufReader::new(reader));
+    let size = copy(&mut r, writer).await?;
     Ok(size)
 }
 
-pub async fn zstd_decode(
-    file: &mut Entry<Archive<File>>,
-    target: &Option<PathBuf>,
-) -> Result<Vec<u8>, Error> {
-    let mut w = ZstdEncoder::new(Vec::new());
-    let _ = copy(file, &mut w).await?;
-    w.shutdown().await?;
-    let buf = w.into_inner();
-    if let Some(target) = target {
-        write_file_and_mtime(target, &buf, file.header()).await?;
-    }
-    Ok(buf)
+pub async fn deflate_encode<R, W>(reader: &mut R, writer: &mut W, level: i32) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut r = DeflateEncoder::with_quality(BufReader::new(reader), Level::Precise(level));
+    let size = copy(&mut r, writer).await?;
+    Ok(size)
 }
 
-pub async fn brotli_encode(file: &PathBuf, target: &PathBuf, level: i32) -> Result<usize, Error> {
-    let mut w = BrotliEncoder::with_quality(Vec::new(), Level::Precise(level));
-    compress(file, &mut w).await?;
-    w.shutdown().await?;
-    let size = write_file(target, &w.into_inner()).await?;
-    copy_mtime(file, target).await?;
+pub async fn deflate_decode<R, W>(reader: &mut R, writer: &mut W) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut r = DeflateDecoder::new(BufReader::new(reader));
+    let size = copy(&mut r, writer).await?;
     Ok(size)
 }
 
-pub async fn brotli_decode(
-    file: &mut Entry<Archive<File>>,
-    target: &Option<PathBuf>,
-) -> Result<Vec<u8>, Error> {
-    let mut w = BrotliDecoder::new(Vec::new());
-    let _ = copy(file, &mut w).await?;
-    w.shutdown().await?;
-    let buf = w.into_inner();
-    if let Some(target) = target {
-        write_file_and_mtime(target, &buf, file.header()).await?;
-    }
-    Ok(buf)
+pub async fn snappy_encode<R, W>(reader: &mut R, writer: &mut W) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    frame::snappy_encode(reader, writer).await
 }
 
-pub async fn deflate_encode(file: &PathBuf, target: &PathBuf, level: i32) -> Result<usize, Error> {
-    let mut w = DeflateEncoder::with_quality(Vec::new(), Level::Precise(level));
-    compress(file, &mut w).await?;
-    w.shutdown().await?;
-    let size = write_file(target, &w.into_inner()).await?;
-    copy_mtime(file, target).await?;
-    Ok(size)
+// the max expansion of raw block, the declared size of block
+// is checked before the buffer is allocated
+const SNAPPY_MAX_RATIO: usize = 32;
+const LZ4_MAX_RATIO: usize = 255;
+
+fn check_declared_size(
+    size: usize,
+    buffer: &[u8],
+    ratio: usize,
+    max_size: Option<u64>,
+) -> Result<(), Error> {
+    if max_size.is_some_and(|max| size as u64 > max) {
+        return Err(Error::BlockTooLarge { size: size as u64 });
+    }
+    if size > buffer.len().saturating_mul(ratio).saturating_add(64) {
+        return Err(Error::InvalidFrame {
+            message: format!("declared size {size} of block is too large"),
+        });
+    }
+    Ok(())
 }
 
-pub async fn deflate_decode(
-    file: &mut Entry<Archive<File>>,
-    target: &Option<PathBuf>,
-) -> Result<Vec<u8>, Error> {
-    let mut w = DeflateEncoder::new(Vec::new());
-    let _ = copy(file, &mut w).await?;
-    w.shutdown().await?;
-    let buf = w.into_inner();
-    if let Some(target) = target {
-        write_file_and_mtime(target, &buf, file.header()).await?;
+/// Read the whole raw block, the block which can't be decoded within
+/// `max_size` is rejected before it is read into memory.
+async fn read_block<R>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+    max_size: Option<u64>,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let Some(max) = max_size else {
+        reader.read_to_end(buffer).await?;
+        return Ok(());
+    };
+    // the raw block of incompressible data is at most 7/6 of
+    // the decoded size with a small overhead
+    let max_read = max.saturating_add(max / 6).saturating_add(64);
+    let size = reader
+        .take(max_read.saturating_add(1))
+        .read_to_end(buffer)
+        .await? as u64;
+    if size > max_read {
+        // the min decoded size of the block
+        return Err(Error::BlockTooLarge {
+            size: (size - 64) / 7 * 6,
+        });
     }
-    Ok(buf)
+    Ok(())
 }
 
-pub async fn snappy_encode(file: &PathBuf, target: &PathBuf) -> Result<usize, Error> {
-    let buf = fs::read(file).await?;
-    let mut w = snap::raw::Encoder::new();
-    let size = write_file(target, &w.compress_vec(&buf)?).await?;
-    copy_mtime(file, target).await?;
-    Ok(size)
+/// Decode snappy framing format, the raw block written by old version
+/// is still supported but it is decoded in memory.
+pub async fn snappy_decode<R, W>(reader: &mut R, writer: &mut W) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    snappy_decode_limited(reader, writer, None).await
 }
 
-pub async fn snappy_decode(
-    file: &mut Entry<Archive<File>>,
-    target: &Option<PathBuf>,
-) -> Result<Vec<u8>, Error> {
-    let size = file.header().size()?;
-    let mut w = snap::raw::Decoder::new();
-    let mut buffer = Vec::with_capacity(size as usize);
-    let mut handle = file.take(size);
-    let _ = handle.read(&mut buffer).await?;
-    let buf = w.decompress_vec(&buffer)?;
-    if let Some(target) = target {
-        write_file_and_mtime(target, &buf, file.header()).await?;
+/// Decode snappy data like `snappy_decode`, the raw block whose declared
+/// size exceeds `max_size` is rejected before it is decompressed.
+pub async fn snappy_decode_limited<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    max_size: Option<u64>,
+) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let (buf, n) = peek::<_, 10>(reader).await?;
+    let mut r = (&buf[..n]).chain(reader);
+    if buf[..n] == *frame::SNAPPY_STREAM_IDENTIFIER {
+        return frame::snappy_decode(&mut r, writer).await;
     }
-    Ok(buf)
+    let mut buffer = vec![];
+    read_block(&mut r, &mut buffer, max_size).await?;
+    check_declared_size(
+        snap::raw::decompress_len(&buffer)?,
+        &buffer,
+        SNAPPY_MAX_RATIO,
+        max_size,
+    )?;
+    let buf = snap::raw::Decoder::new().decompress_vec(&buffer)?;
+    writer.write_all(&buf).await?;
+    Ok(buf.len() as u64)
 }
 
-pub async fn lz4_encode(file: &PathBuf, target: &PathBuf) -> Result<usize, Error> {
-    let buf = fs::read(file).await?;
-    let size = write_file(target, &compress_prepend_size(&buf)).await?;
-    copy_mtime(file, target).await?;
-    Ok(size)
+pub async fn lz4_encode<R, W>(reader: &mut R, writer: &mut W) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    frame::lz4_encode(reader, writer).await
+}
+
+/// Decode lz4 frame format, the size prepended block written by old version
+/// is still supported but it is decoded in memory.
+pub async fn lz4_decode<R, W>(reader: &mut R, writer: &mut W) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    lz4_decode_limited(reader, writer, None).await
 }
 
-pub async fn lz4_decode(
-    file: &mut Entry<Archive<File>>,
-    target: &Option<PathBuf>,
-) -> Result<Vec<u8>, Error> {
-    let size = file.header().size()?;
-    let mut buffer = Vec::with_capacity(size as usize);
-    let mut handle = file.take(size);
-    let _ = handle.read(&mut buffer).await?;
-    let (_, buf) = uncompressed_size(&buffer)?;
-    if let Some(target) = target {
-        write_file_and_mtime(target, buf, file.header()).await?;
+/// Decode lz4 data like `lz4_decode`, the size prepended block whose
+/// declared size exceeds `max_size` is rejected before it is decompressed.
+pub async fn lz4_decode_limited<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    max_size: Option<u64>,
+) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let (buf, n) = peek::<_, 4>(reader).await?;
+    let mut r = (&buf[..n]).chain(reader);
+    if buf[..n] == *frame::LZ4_MAGIC {
+        return frame::lz4_decode(&mut r, writer).await;
+    }
+    let mut buffer = vec![];
+    read_block(&mut r, &mut buffer, max_size).await?;
+    if let Some(size) = buffer.get(..4) {
+        let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]);
+        check_declared_size(size as usize, &buffer, LZ4_MAX_RATIO, max_size)?;
     }
-    Ok(buf.to_owned())
+    let buf = decompress_size_prepended(&buffer)?;
+    writer.write_all(&buf).await?;
+    Ok(buf.len() as u64)
 }
 
-pub async fn xz_encode(file: &PathBuf, target: &PathBuf, level: i32) -> Result<usize, Error> {
-    let mut w = XzEncoder::with_quality(Vec::new(), Level::Precise(level));
-    compress(file, &mut w).await?;
-    w.shutdown().await?;
-    let size = write_file(target, &w.into_inner()).await?;
-    copy_mtime(file, target).await?;
+pub async fn xz_encode<R, W>(reader: &mut R, writer: &mut W, level: i32) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut r = XzEncoder::with_quality(BufReader::new(reader), Level::Precise(level));
+    let size = copy(&mut r, writer).await?;
     Ok(size)
 }
 
-pub async fn xz_decode(
-    file: &mut Entry<Archive<File>>,
-    target: &Option<PathBuf>,
-) -> Result<Vec<u8>, Error> {
-    let mut w = XzDecoder::new(Vec::new());
-    let _ = copy(file, &mut w).await?;
-    w.shutdown().await?;
-    let buf = w.into_inner();
-    if let Some(target) = target {
-        write_file_and_mtime(target, &buf, file.header()).await?;
-    }
-    Ok(buf)
+pub async fn xz_decode<R, W>(reader: &mut R, writer: &mut W) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut r = XzDecoder::new(BufReader::new(reader));
+    let size = copy(&mut r, writer).await?;
+    Ok(size)
 }
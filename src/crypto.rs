@@ -0,0 +1,475 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The data of each entry is compressed first and then encrypted in chunks,
+// the nonce of chunk is the random prefix of entry with the chunk counter
+// and the last flag (the STREAM construction), so the chunks can't be
+// reordered or truncated. The path of entry is the associated data of
+// its chunks, so the data can't be swapped between entries. The parameters
+// of encryption and a sealed constant to check the key are stored in the
+// manifest.
+
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{Aead, Generate, KeyInit, Payload};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use chacha20poly1305::ChaCha20Poly1305;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::codec::{BoxFuture, Codec, Compression, Reader, Writer};
+use super::error::Error;
+use super::manifest::Manifest;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const PREFIX_SIZE: usize = 7;
+const KEY_SIZE: usize = 32;
+const SALT_SIZE: usize = 16;
+/// The constant is sealed in the manifest to check the key.
+const VERIFIER: &[u8] = b"archiver";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Cipher {
+    #[default]
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl FromStr for Cipher {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "aes-256-gcm" | "aes" => Ok(Cipher::Aes256Gcm),
+            "chacha20-poly1305" | "chacha20" => Ok(Cipher::ChaCha20Poly1305),
+            _ => Err(Error::InvalidCipher {
+                cipher: value.to_string(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cipher::Aes256Gcm => write!(f, "aes-256-gcm"),
+            Cipher::ChaCha20Poly1305 => write!(f, "chacha20-poly1305"),
+        }
+    }
+}
+
+/// Source of the encryption key.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeySource {
+    /// The key is derived from the passphrase with Argon2id
+    Passphrase(String),
+    /// The key file contains 32 bytes, or 64 hex characters
+    File(PathBuf),
+}
+
+impl KeySource {
+    fn derive(&self, salt: &[u8]) -> Result<[u8; KEY_SIZE], Error> {
+        let mut key = [0; KEY_SIZE];
+        match self {
+            KeySource::Passphrase(passphrase) => {
+                argon2::Argon2::default()
+                    .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                    .map_err(|err| Error::InvalidKey {
+                        message: err.to_string(),
+                    })?;
+            }
+            KeySource::File(path) => key = read_key_file(path)?,
+        }
+        Ok(key)
+    }
+}
+
+/// Read the key file of 32 bytes, or 64 hex characters.
+pub(crate) fn read_key_file(path: &Path) -> Result<[u8; KEY_SIZE], Error> {
+    let data = std::fs::read(path)?;
+    let mut key = [0; KEY_SIZE];
+    if data.len() == KEY_SIZE {
+        key.copy_from_slice(&data);
+        return Ok(key);
+    }
+    let hex = std::str::from_utf8(&data).unwrap_or_default().trim();
+    if hex.len() != KEY_SIZE * 2 {
+        return Err(Error::InvalidKey {
+            message: "key file must be 32 bytes or 64 hex characters".to_string(),
+        });
+    }
+    for (index, value) in key.iter_mut().enumerate() {
+        *value = hex
+            .get(index * 2..index * 2 + 2)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| Error::InvalidKey {
+                message: "key file has invalid hex character".to_string(),
+            })?;
+    }
+    Ok(key)
+}
+
+/// Encryption of archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Encryption {
+    pub cipher: Cipher,
+    pub key: KeySource,
+    /// Encrypt the paths of entries too
+    pub names: bool,
+}
+
+/// Parameters of encryption stored in the manifest.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEncryption {
+    pub cipher: String,
+    /// Salt of Argon2id as base64
+    pub salt: String,
+    /// Whether the paths of entries are encrypted
+    pub names: bool,
+    /// The sealed constant as base64 to check the key
+    pub verifier: String,
+}
+
+#[derive(Clone)]
+enum AeadCipher {
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+/// Crypter seals and opens the data with the derived key.
+#[derive(Clone)]
+pub(crate) struct Crypter {
+    cipher: AeadCipher,
+    names: bool,
+}
+
+fn decrypt_failed(message: &str) -> Error {
+    Error::Decrypt {
+        message: message.to_string(),
+    }
+}
+
+impl Crypter {
+    fn new(cipher: Cipher, key: &[u8; KEY_SIZE], names: bool) -> Self {
+        let cipher = match cipher {
+            Cipher::Aes256Gcm => AeadCipher::Aes256Gcm(Box::new(Aes256Gcm::new(&(*key).into()))),
+            Cipher::ChaCha20Poly1305 => {
+                AeadCipher::ChaCha20Poly1305(Box::new(ChaCha20Poly1305::new(&(*key).into())))
+            }
+        };
+        Self { cipher, names }
+    }
+    /// Create the crypter for new archive, returns it with
+    /// the parameters which are stored in the manifest.
+    pub(crate) fn create(encryption: &Encryption) -> Result<(Self, ManifestEncryption), Error> {
+        let salt = <[u8; SALT_SIZE]>::generate();
+        let key = encryption.key.derive(&salt)?;
+        let crypter = Self::new(encryption.cipher, &key, encryption.names);
+        let info = ManifestEncryption {
+            cipher: encryption.cipher.to_string(),
+            salt: STANDARD.encode(salt),
+            names: encryption.names,
+            verifier: STANDARD.encode(crypter.seal_message(VERIFIER)?),
+        };
+        Ok((crypter, info))
+    }
+    /// Create the crypter of the archive, the key is checked by the verifier.
+    pub(crate) fn open(info: &ManifestEncryption, key: &KeySource) -> Result<Self, Error> {
+        let cipher: Cipher = info.cipher.parse()?;
+        let salt = STANDARD
+            .decode(&info.salt)
+            .map_err(|_| decrypt_failed("invalid salt"))?;
+        let crypter = Self::new(cipher, &key.derive(&salt)?, info.names);
+        let verifier = STANDARD
+            .decode(&info.verifier)
+            .map_err(|_| decrypt_failed("invalid verifier"))?;
+        match crypter.open_message(&verifier) {
+            Ok(value) if value == VERIFIER => Ok(crypter),
+            _ => Err(Error::WrongKey),
+        }
+    }
+    fn seal(&self, nonce: [u8; NONCE_SIZE], data: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        let payload = Payload { msg: data, aad };
+        let result = match &self.cipher {
+            AeadCipher::Aes256Gcm(cipher) => cipher.encrypt(&nonce.into(), payload),
+            AeadCipher::ChaCha20Poly1305(cipher) => cipher.encrypt(&nonce.into(), payload),
+        };
+        result.map_err(|_| Error::InvalidKey {
+            message: "encrypt failed".to_string(),
+        })
+    }
+    fn unseal(&self, nonce: [u8; NONCE_SIZE], data: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        let payload = Payload { msg: data, aad };
+        let result = match &self.cipher {
+            AeadCipher::Aes256Gcm(cipher) => cipher.decrypt(&nonce.into(), payload),
+            AeadCipher::ChaCha20Poly1305(cipher) => cipher.decrypt(&nonce.into(), payload),
+        };
+        result.map_err(|_| decrypt_failed("authentication failed"))
+    }
+    /// Seal the small data with random nonce, the nonce is prepended.
+    pub(crate) fn seal_message(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = <[u8; NONCE_SIZE]>::generate();
+        let mut sealed = nonce.to_vec();
+        sealed.extend(self.seal(nonce, data, &[])?);
+        Ok(sealed)
+    }
+    pub(crate) fn open_message(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.len() < NONCE_SIZE {
+            return Err(decrypt_failed("message is truncated"));
+        }
+        let (nonce, data) = data.split_at(NONCE_SIZE);
+        self.unseal(nonce.try_into().unwrap_or_default(), data, &[])
+    }
+    /// Encrypt the path of entry if names are encrypted.
+    pub(crate) fn encrypt_name(&self, name: &Path) -> Result<PathBuf, Error> {
+        if !self.names {
+            return Ok(name.to_path_buf());
+        }
+        let name = name.to_string_lossy();
+        Ok(URL_SAFE_NO_PAD
+            .encode(self.seal_message(name.as_bytes())?)
+            .into())
+    }
+    /// Decrypt the path of entry if names are encrypted.
+    pub(crate) fn decrypt_name(&self, name: &Path) -> Result<PathBuf, Error> {
+        if !self.names {
+            return Ok(name.to_path_buf());
+        }
+        let name = name.to_string_lossy();
+        let data = URL_SAFE_NO_PAD
+            .decode(name.trim_end_matches('/'))
+            .map_err(|_| decrypt_failed("invalid name"))?;
+        let name = String::from_utf8(self.open_message(&data)?)
+            .map_err(|_| decrypt_failed("invalid name"))?;
+        Ok(name.into())
+    }
+    /// Encrypt the data in chunks, the path of entry is the associated data.
+    async fn encrypt(
+        &self,
+        reader: &mut Reader<'_>,
+        writer: &mut Writer<'_>,
+        path: &str,
+    ) -> Result<u64, Error> {
+        let prefix = <[u8; PREFIX_SIZE]>::generate();
+        writer.write_all(&prefix).await?;
+        let mut size = PREFIX_SIZE as u64;
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let mut next = vec![0; CHUNK_SIZE];
+        let mut length = read_full(reader, &mut chunk).await?;
+        let mut counter = 0;
+        loop {
+            // read ahead to know whether it is the last chunk
+            let next_length = if length == CHUNK_SIZE {
+                read_full(reader, &mut next).await?
+            } else {
+                0
+            };
+            let last = next_length == 0;
+            let sealed = self.seal(
+                chunk_nonce(&prefix, counter, last)?,
+                &chunk[..length],
+                path.as_bytes(),
+            )?;
+            writer.write_all(&sealed).await?;
+            size += sealed.len() as u64;
+            if last {
+                break;
+            }
+            std::mem::swap(&mut chunk, &mut next);
+            length = next_length;
+            counter += 1;
+        }
+        writer.flush().await?;
+        Ok(size)
+    }
+    async fn decrypt(
+        &self,
+        reader: &mut Reader<'_>,
+        writer: &mut Writer<'_>,
+        path: &str,
+    ) -> Result<u64, Error> {
+        let mut prefix = [0; PREFIX_SIZE];
+        if read_full(reader, &mut prefix).await? != PREFIX_SIZE {
+            return Err(decrypt_failed("data is truncated"));
+        }
+        let mut size = 0;
+        let mut chunk = vec![0; CHUNK_SIZE + TAG_SIZE];
+        let mut next = vec![0; CHUNK_SIZE + TAG_SIZE];
+        let mut length = read_full(reader, &mut chunk).await?;
+        let mut counter = 0;
+        loop {
+            let next_length = if length == chunk.len() {
+                read_full(reader, &mut next).await?
+            } else {
+                0
+            };
+            let last = next_length == 0;
+            let data = self.unseal(
+                chunk_nonce(&prefix, counter, last)?,
+                &chunk[..length],
+                path.as_bytes(),
+            )?;
+            writer.write_all(&data).await?;
+            size += data.len() as u64;
+            if last {
+                break;
+            }
+            std::mem::swap(&mut chunk, &mut next);
+            length = next_length;
+            counter += 1;
+        }
+        writer.flush().await?;
+        Ok(size)
+    }
+}
+
+fn chunk_nonce(
+    prefix: &[u8; PREFIX_SIZE],
+    counter: u64,
+    last: bool,
+) -> Result<[u8; NONCE_SIZE], Error> {
+    let counter = u32::try_from(counter).map_err(|_| decrypt_failed("too many chunks"))?;
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[..PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[PREFIX_SIZE..NONCE_SIZE - 1].copy_from_slice(&counter.to_be_bytes());
+    nonce[NONCE_SIZE - 1] = last as u8;
+    Ok(nonce)
+}
+
+/// Read until the buffer is full or the end of reader.
+async fn read_full(reader: &mut Reader<'_>, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut length = 0;
+    while length < buf.len() {
+        let size = reader.read(&mut buf[length..]).await?;
+        if size == 0 {
+            break;
+        }
+        length += size;
+    }
+    Ok(length)
+}
+
+/// Size of the sealed message as base64.
+pub(crate) fn sealed_len(size: usize) -> usize {
+    (size + NONCE_SIZE + TAG_SIZE).div_ceil(3) * 4
+}
+
+/// Codec encrypts the compressed data of the entry, the compression
+/// and encryption run concurrently through a pipe.
+struct EncryptedCodec {
+    compression: Compression,
+    crypter: Crypter,
+    path: String,
+}
+
+impl Codec for EncryptedCodec {
+    fn name(&self) -> &str {
+        self.compression.name()
+    }
+    fn encode<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+        level: i32,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move {
+            let (mut w, mut r) = tokio::io::duplex(CHUNK_SIZE);
+            let compress = async {
+                self.compression.encode(reader, &mut w, level).await?;
+                w.shutdown().await?;
+                Ok::<_, Error>(())
+            };
+            let (_, size) =
+                tokio::try_join!(compress, self.crypter.encrypt(&mut r, writer, &self.path))?;
+            Ok(size)
+        })
+    }
+    fn decode<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        self.decode_limited(reader, writer, None)
+    }
+    fn decode_limited<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+        max_size: Option<u64>,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move {
+            let (mut w, mut r) = tokio::io::duplex(CHUNK_SIZE);
+            let decrypt = async {
+                self.crypter.decrypt(reader, &mut w, &self.path).await?;
+                w.shutdown().await?;
+                Ok::<_, Error>(())
+            };
+            let decompress = async {
+                let size = self
+                    .compression
+                    .decode_limited(&mut r, writer, max_size)
+                    .await?;
+                // the remaining chunks must be authenticated too
+                tokio::io::copy(&mut r, &mut tokio::io::sink()).await?;
+                Ok::<_, Error>(size)
+            };
+            let (_, size) = tokio::try_join!(decrypt, decompress)?;
+            Ok(size)
+        })
+    }
+}
+
+/// Codec of the entry whose original path is `path`, the compressed data
+/// is encrypted if crypter is set.
+pub(crate) fn entry_codec(
+    compression: &Compression,
+    crypter: Option<&Crypter>,
+    path: &str,
+) -> Arc<dyn Codec> {
+    match crypter {
+        Some(crypter) => Arc::new(EncryptedCodec {
+            compression: compression.clone(),
+            crypter: crypter.clone(),
+            path: path.to_string(),
+        }),
+        None => Arc::new(compression.clone()),
+    }
+}
+
+/// Crypter of the archive, the sealed files of manifest are opened.
+/// It is none if the archive is not encrypted.
+pub(crate) fn open_manifest(
+    manifest: Option<&mut Manifest>,
+    key: Option<&KeySource>,
+) -> Result<Option<Crypter>, Error> {
+    let Some(manifest) = manifest else {
+        return Ok(None);
+    };
+    let Some(info) = manifest.encryption.as_ref() else {
+        return Ok(None);
+    };
+    let Some(key) = key else {
+        return Err(Error::InvalidKey {
+            message: "archive is encrypted, key is required".to_string(),
+        });
+    };
+    let crypter = Crypter::open(info, key)?;
+    manifest.unseal(&crypter)?;
+    Ok(Some(crypter))
+}
@@ -0,0 +1,443 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Files are split into content-defined chunks with gear hash, so the same
+// content shifted by insertions still produces the same chunks. Each unique
+// chunk is compressed and stored once as a chunk entry, and the file entry
+// stores the recipe of its chunks instead of the data.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+use tokio_stream::StreamExt;
+use tokio_tar::{Builder, EntryType, Header};
+
+use super::archiver::{SourceEntry, uuid};
+use super::codec::{BoxFuture, Codec, Compression, Reader, Writer};
+use super::crypto::{Crypter, entry_codec};
+use super::error::Error;
+use super::index::{
+    ArchiveIndex, IndexEntry, index_entry, read_index, scan_index, stream_position,
+};
+use super::limit::{Limit, Limits};
+use super::manifest::{HashReader, ManifestFile, manifest_mtime, open_archive_at};
+use super::pax::{PaxRecords, append_pax, read_pax};
+
+/// Directory of the chunk entries, they are not files of archive.
+pub const CHUNK_DIR: &str = ".archiver-chunks/";
+/// PAX record of the chunked file, it is the compressed size of its chunks.
+pub(crate) const PAX_CHUNKED_SIZE: &str = "ARCHIVER.chunked.size";
+
+// the recipe is decoded in memory, each chunk id takes about 40 bytes,
+// so it lists about 400K chunks, which are 25 GiB on average
+const MAX_RECIPE_SIZE: u64 = 16 * 1024 * 1024;
+
+const MIN_CHUNK_SIZE: usize = 16 * 1024;
+const MAX_CHUNK_SIZE: usize = 256 * 1024;
+// the cut point is found every 64 KiB on average
+const CHUNK_MASK: u64 = (1 << 16) - 1;
+
+/// Random values of bytes for gear hash, generated by splitmix64.
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+pub(crate) fn is_chunk(path: &str) -> bool {
+    path.starts_with(CHUNK_DIR)
+}
+
+/// Compressed size of the chunks of file, it is none if the file is not chunked.
+pub(crate) fn chunked_size(records: &PaxRecords) -> Result<Option<u64>, Error> {
+    let Some(value) = records.get(PAX_CHUNKED_SIZE) else {
+        return Ok(None);
+    };
+    let size = std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| Error::InvalidFrame {
+            message: "chunked size is invalid".to_string(),
+        })?;
+    Ok(Some(size))
+}
+
+/// Length of the next chunk, the data is cut where the low bits of gear hash
+/// are zero, between the min and max chunk size.
+fn cut_point(data: &[u8]) -> usize {
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    if end <= MIN_CHUNK_SIZE {
+        return end;
+    }
+    let mut hash: u64 = 0;
+    for (i, byte) in data[MIN_CHUNK_SIZE..end].iter().enumerate() {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        if hash & CHUNK_MASK == 0 {
+            return MIN_CHUNK_SIZE + i + 1;
+        }
+    }
+    end
+}
+
+/// Split the data of reader into content-defined chunks.
+struct Chunker<R> {
+    reader: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: AsyncRead + Unpin> Chunker<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::with_capacity(MAX_CHUNK_SIZE),
+            eof: false,
+        }
+    }
+    async fn next(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let mut data = [0; 64 * 1024];
+        while !self.eof && self.buf.len() < MAX_CHUNK_SIZE {
+            let size = self.reader.read(&mut data).await?;
+            if size == 0 {
+                self.eof = true;
+            }
+            self.buf.extend_from_slice(&data[..size]);
+        }
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let size = cut_point(&self.buf);
+        Ok(Some(self.buf.drain(..size).collect()))
+    }
+    fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Chunks of the file in order, the entry of each chunk
+/// is `CHUNK_DIR` with the id.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Recipe {
+    chunks: Vec<String>,
+}
+
+/// Writer of the chunked files, each unique chunk is stored once.
+pub(crate) struct ChunkWriter {
+    crypter: Option<Crypter>,
+    compression: Compression,
+    level: i32,
+    // the stored chunks by blake3 hash with their id and compressed size,
+    // the ids are random as the hash reveals the content of encrypted archive
+    ids: HashMap<blake3::Hash, (String, u64)>,
+}
+
+impl ChunkWriter {
+    pub(crate) fn new(crypter: Option<Crypter>, compression: Compression, level: i32) -> Self {
+        Self {
+            crypter,
+            compression,
+            level,
+            ids: HashMap::new(),
+        }
+    }
+    /// Append the chunks of file which are not stored yet, then the file entry
+    /// with the recipe of its chunks. Returns the manifest record of the file.
+    pub(crate) async fn append(
+        &mut self,
+        a: &mut Builder<File>,
+        index: &mut ArchiveIndex,
+        entry: &SourceEntry,
+        name: &Path,
+        mut records: PaxRecords,
+    ) -> Result<ManifestFile, Error> {
+        let (crypter, compression, level) = (self.crypter.as_ref(), &self.compression, self.level);
+        let ids = &mut self.ids;
+        let mut chunker = Chunker::new(HashReader::new(File::open(&entry.path).await?));
+        let mut recipe = Recipe::default();
+        let mut chunked_size = 0;
+        while let Some(chunk) = chunker.next().await? {
+            let hash = blake3::hash(&chunk);
+            if let Some((id, size)) = ids.get(&hash) {
+                recipe.chunks.push(id.clone());
+                chunked_size += size;
+                continue;
+            }
+            let id = uuid();
+            let path = format!("{CHUNK_DIR}{id}");
+            let mut data = vec![];
+            entry_codec(compression, crypter, &path)
+                .encode(&mut &chunk[..], &mut data, level)
+                .await?;
+            let offset = stream_position(a).await?;
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Regular);
+            header.set_mode(0o644);
+            header.set_size(data.len() as u64);
+            a.append_data(&mut header, &path, &data[..]).await?;
+            index_entry(
+                a,
+                index,
+                Path::new(&path),
+                offset,
+                data.len() as u64,
+                compression,
+            )
+            .await?;
+            chunked_size += data.len() as u64;
+            ids.insert(hash, (id.clone(), data.len() as u64));
+            recipe.chunks.push(id);
+        }
+        let r = chunker.into_inner();
+
+        let json = serde_json::to_vec(&recipe).map_err(|err| Error::Json { source: err })?;
+        // the recipe larger than the limit can't be decoded
+        if json.len() as u64 > MAX_RECIPE_SIZE {
+            return Err(Error::LimitExceeded {
+                path: entry.name.to_string_lossy().to_string(),
+                limit: Limit::EntrySize(MAX_RECIPE_SIZE),
+            });
+        }
+        let mut data = vec![];
+        entry_codec(compression, crypter, &entry.name.to_string_lossy())
+            .encode(&mut &json[..], &mut data, level)
+            .await?;
+        records.insert(
+            PAX_CHUNKED_SIZE.to_string(),
+            chunked_size.to_string().into_bytes(),
+        );
+        let offset = stream_position(a).await?;
+        append_pax(a, &records).await?;
+        let mut header = Header::new_gnu();
+        header.set_metadata(&entry.meta);
+        header.set_size(data.len() as u64);
+        a.append_data(&mut header, name, &data[..]).await?;
+        index_entry(a, index, name, offset, data.len() as u64, compression).await?;
+        Ok(ManifestFile {
+            path: entry.name.to_string_lossy().to_string(),
+            size: r.size(),
+            mode: header.mode()?,
+            checksum: r.checksum(),
+            mtime: Some(manifest_mtime(&entry.meta)),
+        })
+    }
+}
+
+/// Offset and length of the stored chunks, they are found by the index.
+pub(crate) struct ChunkStore {
+    source: String,
+    chunks: HashMap<String, (u64, u64)>,
+}
+
+impl ChunkStore {
+    /// The chunks must be within the archive file, as their data
+    /// is read by the offset and length of index.
+    pub(crate) fn new(source: &str, index: &ArchiveIndex) -> Result<Self, Error> {
+        let size = std::fs::metadata(source)?.len();
+        let mut chunks = HashMap::new();
+        for entry in index.entries.iter().filter(|entry| is_chunk(&entry.path)) {
+            if entry
+                .offset
+                .checked_add(entry.length)
+                .is_none_or(|end| end > size)
+            {
+                return Err(Error::InvalidIndex {
+                    message: format!("chunk {} is out of archive", entry.path),
+                });
+            }
+            chunks.insert(entry.path.clone(), (entry.offset, entry.length));
+        }
+        Ok(Self {
+            source: source.to_string(),
+            chunks,
+        })
+    }
+    /// Open the chunks of archive, it is scanned if the archive has no index.
+    pub(crate) async fn open(source: &str, compression: &Compression) -> Result<Self, Error> {
+        let index = match read_index(source).await? {
+            Some(index) => index,
+            None => scan_index(source, compression).await?.0,
+        };
+        Self::new(source, &index)
+    }
+}
+
+/// Codec of the chunked file, the chunks of its recipe are read
+/// from the archive and decoded in order, the entry data is ignored.
+struct ChunkedCodec {
+    compression: Compression,
+    source: String,
+    // codec, offset and length of each chunk
+    chunks: Vec<(Arc<dyn Codec>, u64, u64)>,
+}
+
+impl Codec for ChunkedCodec {
+    fn name(&self) -> &str {
+        self.compression.name()
+    }
+    fn encode<'a>(
+        &'a self,
+        _reader: &'a mut Reader<'_>,
+        _writer: &'a mut Writer<'_>,
+        _level: i32,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(
+            async move { Err(std::io::Error::other("chunked file is encoded by chunks").into()) },
+        )
+    }
+    fn decode<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        self.decode_limited(reader, writer, None)
+    }
+    fn decode_limited<'a>(
+        &'a self,
+        _reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+        max_size: Option<u64>,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move {
+            let mut file = File::open(&self.source).await?;
+            let mut size = 0;
+            for (codec, offset, length) in self.chunks.iter() {
+                file.seek(SeekFrom::Start(*offset)).await?;
+                let max_size = max_size.map(|max| max.saturating_sub(size));
+                size += codec
+                    .decode_limited(&mut (&mut file).take(*length), writer, max_size)
+                    .await?;
+            }
+            Ok(size)
+        })
+    }
+}
+
+/// Decode the recipe of chunked file, it fails if the recipe is larger
+/// than `MAX_RECIPE_SIZE`.
+async fn read_recipe(
+    codec: &dyn Codec,
+    reader: &mut Reader<'_>,
+    path: &str,
+) -> Result<Recipe, Error> {
+    let limits = Limits {
+        entry_size: Some(MAX_RECIPE_SIZE),
+        ..Default::default()
+    };
+    let mut w = limits.entry(path, 0).writer(vec![]);
+    let max_size = w.max_size();
+    let result = codec.decode_limited(reader, &mut w, max_size).await;
+    w.check(result)?;
+    let json = w.into_inner();
+    serde_json::from_slice(&json).map_err(|err| Error::Json { source: err })
+}
+
+/// Codec of the entry whose original path is `path`, with the compressed
+/// size of chunked file. The recipe of chunked file is read from the entry,
+/// and its compressed size is the sum of its chunks found by the index,
+/// so the ratio limit doesn't trust the size recorded by PAX. The recipe
+/// larger than `MAX_RECIPE_SIZE` is rejected before it is parsed. The chunk
+/// store is opened once the first chunked file is decoded.
+pub(crate) async fn resolve_codec(
+    records: &PaxRecords,
+    path: &str,
+    reader: &mut Reader<'_>,
+    store: &mut Option<Arc<ChunkStore>>,
+    source: &str,
+    compression: &Compression,
+    crypter: Option<&Crypter>,
+) -> Result<(Arc<dyn Codec>, Option<u64>), Error> {
+    let codec = entry_codec(compression, crypter, path);
+    if chunked_size(records)?.is_none() {
+        return Ok((codec, None));
+    }
+    let store = match store {
+        Some(store) => store.clone(),
+        None => store
+            .insert(Arc::new(ChunkStore::open(source, compression).await?))
+            .clone(),
+    };
+    let recipe = read_recipe(&*codec, reader, path).await?;
+    let chunks = recipe
+        .chunks
+        .iter()
+        .map(|id| {
+            let path = format!("{CHUNK_DIR}{id}");
+            let Some((offset, length)) = store.chunks.get(&path) else {
+                return Err(Error::InvalidIndex {
+                    message: format!("chunk {id} is not found"),
+                });
+            };
+            Ok((entry_codec(compression, crypter, &path), *offset, *length))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let compressed = chunks.iter().map(|(_, _, length)| length).sum();
+    let codec = ChunkedCodec {
+        compression: compression.clone(),
+        source: store.source.clone(),
+        chunks,
+    };
+    Ok((Arc::new(codec), Some(compressed)))
+}
+
+/// Drop the chunk entries which no recipe of the kept entries refers to,
+/// as the chunked files of them are deleted or replaced. The entries are
+/// paired with their decrypted path.
+pub(crate) async fn drop_unused_chunks(
+    source: &str,
+    entries: Vec<(String, IndexEntry)>,
+    compression: &Compression,
+    crypter: Option<&Crypter>,
+) -> Result<Vec<IndexEntry>, Error> {
+    let mut used = HashSet::new();
+    if entries.iter().any(|(path, _)| is_chunk(path)) {
+        for (path, entry) in entries.iter().filter(|(path, _)| !is_chunk(path)) {
+            let mut r = open_archive_at(source, entry.header)?;
+            let mut files = r.entries()?;
+            let Some(file) = files.next().await else {
+                continue;
+            };
+            let mut f = file?;
+            let pax = read_pax(&mut f).await?;
+            if chunked_size(&pax)?.is_none() {
+                continue;
+            }
+            let codec = entry_codec(compression, crypter, path);
+            let recipe = read_recipe(&*codec, &mut f, path).await?;
+            used.extend(
+                recipe
+                    .chunks
+                    .into_iter()
+                    .map(|id| format!("{CHUNK_DIR}{id}")),
+            );
+        }
+    }
+    Ok(entries
+        .into_iter()
+        .filter(|(path, _)| !is_chunk(path) || used.contains(path))
+        .map(|(_, entry)| entry)
+        .collect())
+}
@@ -14,6 +14,8 @@
 
 use snafu::Snafu;
 
+use super::limit::Limit;
+
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("Arg is invalid {path}"))]
@@ -36,6 +38,44 @@ pub enum Error {
     Lz4Decompress {
         source: lz4_flex::block::DecompressError,
     },
+    #[snafu(display("Lz4 compress {source}"))]
+    Lz4Compress {
+        source: lz4_flex::block::CompressError,
+    },
+    #[snafu(display("Join {source}"))]
+    Join { source: tokio::task::JoinError },
+    #[snafu(display("Json {source}"))]
+    Json { source: serde_json::Error },
+    #[snafu(display("Compression mismatch, archive is {expected} but {actual} is specified"))]
+    CompressionMismatch { expected: String, actual: String },
+    #[snafu(display("Frame is invalid {message}"))]
+    InvalidFrame { message: String },
+    #[snafu(display("Block is too large, its declared size is {size}"))]
+    BlockTooLarge { size: u64 },
+    #[snafu(display("Path is unsafe {path}, {reason}"))]
+    UnsafePath { path: String, reason: String },
+    #[snafu(display("Limit exceeded {path}, {limit}"))]
+    LimitExceeded { path: String, limit: Limit },
+    #[snafu(display("Verify failed, {count} files are corrupted"))]
+    Corrupted { count: usize },
+    #[snafu(display("Cipher is invalid {cipher}"))]
+    InvalidCipher { cipher: String },
+    #[snafu(display("Key is invalid {message}"))]
+    InvalidKey { message: String },
+    #[snafu(display("Key is wrong, the archive can't be decrypted"))]
+    WrongKey,
+    #[snafu(display("Decrypt failed {message}"))]
+    Decrypt { message: String },
+    #[snafu(display("Signature is invalid {message}"))]
+    InvalidSignature { message: String },
+    #[snafu(display("Signer {fingerprint} is not trusted"))]
+    UntrustedSigner { fingerprint: String },
+    #[snafu(display("Index is invalid {message}"))]
+    InvalidIndex { message: String },
+    #[snafu(display("Incremental archive is invalid {message}"))]
+    InvalidIncremental { message: String },
+    #[snafu(display("Archive is being appended or its append is interrupted {path}"))]
+    AppendInProgress { path: String },
 }
 
 impl From<std::io::Error> for Error {
@@ -55,3 +95,15 @@ impl From<lz4_flex::block::DecompressError> for Error {
         Error::Lz4Decompress { source: err }
     }
 }
+
+impl From<lz4_flex::block::CompressError> for Error {
+    fn from(err: lz4_flex::block::CompressError) -> Self {
+        Error::Lz4Compress { source: err }
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(err: tokio::task::JoinError) -> Self {
+        Error::Join { source: err }
+    }
+}
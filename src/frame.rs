@@ -0,0 +1,275 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Snappy and lz4 only offer block apis in async context, so the framing
+// formats are implemented here to stream them with bounded memory.
+// Snappy: https://github.com/google/snappy/blob/main/framing_format.txt
+// Lz4: https://github.com/lz4/lz4/blob/dev/doc/lz4_Frame_format.md
+
+use lz4_flex::block;
+use std::hash::Hasher;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use twox_hash::XxHash32;
+
+use super::error::Error;
+
+const BLOCK_SIZE: usize = 64 * 1024;
+
+pub(crate) const SNAPPY_STREAM_IDENTIFIER: &[u8] = b"\xff\x06\x00\x00sNaPpY";
+const SNAPPY_COMPRESSED: u8 = 0x00;
+const SNAPPY_UNCOMPRESSED: u8 = 0x01;
+const SNAPPY_IDENTIFIER: u8 = 0xff;
+
+pub(crate) const LZ4_MAGIC: &[u8] = &[0x04, 0x22, 0x4d, 0x18];
+// version 01, independent blocks
+const LZ4_FLG: u8 = 0x60;
+// 64KB max block size
+const LZ4_BD: u8 = 0x40;
+const LZ4_UNCOMPRESSED_BIT: u32 = 0x8000_0000;
+
+fn invalid_frame(message: &str) -> Error {
+    Error::InvalidFrame {
+        message: message.to_string(),
+    }
+}
+
+/// Read until the buffer is full or the reader reaches eof,
+/// returns the size of read data.
+pub(crate) async fn read_full<R>(reader: &mut R, buf: &mut [u8]) -> Result<usize, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut size = 0;
+    while size < buf.len() {
+        let n = reader.read(&mut buf[size..]).await?;
+        if n == 0 {
+            break;
+        }
+        size += n;
+    }
+    Ok(size)
+}
+
+fn snappy_checksum(data: &[u8]) -> u32 {
+    let crc = crc32c::crc32c(data);
+    crc.rotate_right(15).wrapping_add(0xa282_ead8)
+}
+
+pub(crate) async fn snappy_encode<R, W>(reader: &mut R, writer: &mut W) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut encoder = snap::raw::Encoder::new();
+    let mut input = vec![0; BLOCK_SIZE];
+    let mut output = vec![0; snap::raw::max_compress_len(BLOCK_SIZE)];
+    writer.write_all(SNAPPY_STREAM_IDENTIFIER).await?;
+    let mut written = SNAPPY_STREAM_IDENTIFIER.len() as u64;
+    loop {
+        let n = read_full(reader, &mut input).await?;
+        if n == 0 {
+            break;
+        }
+        let data = &input[..n];
+        let size = encoder.compress(data, &mut output)?;
+        let (chunk_type, body) = if size < n {
+            (SNAPPY_COMPRESSED, &output[..size])
+        } else {
+            (SNAPPY_UNCOMPRESSED, data)
+        };
+        let len = (body.len() + 4) as u32;
+        let header = [chunk_type, len as u8, (len >> 8) as u8, (len >> 16) as u8];
+        writer.write_all(&header).await?;
+        writer
+            .write_all(&snappy_checksum(data).to_le_bytes())
+            .await?;
+        writer.write_all(body).await?;
+        written += header.len() as u64 + len as u64;
+    }
+    Ok(written)
+}
+
+pub(crate) async fn snappy_decode<R, W>(reader: &mut R, writer: &mut W) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut decoder = snap::raw::Decoder::new();
+    let mut chunk = vec![];
+    let mut output = vec![0; BLOCK_SIZE];
+    let mut header = [0; 4];
+    let mut size = 0;
+    let mut identified = false;
+    loop {
+        match read_full(reader, &mut header).await? {
+            0 => break,
+            4 => {}
+            _ => return Err(invalid_frame("snappy chunk header is truncated")),
+        }
+        let len = u32::from_le_bytes([header[1], header[2], header[3], 0]) as usize;
+        chunk.resize(len, 0);
+        reader.read_exact(&mut chunk).await?;
+        match header[0] {
+            SNAPPY_IDENTIFIER => {
+                if chunk != SNAPPY_STREAM_IDENTIFIER[4..] {
+                    return Err(invalid_frame("snappy stream identifier is invalid"));
+                }
+                identified = true;
+            }
+            SNAPPY_COMPRESSED | SNAPPY_UNCOMPRESSED => {
+                if !identified {
+                    return Err(invalid_frame("snappy stream identifier is missing"));
+                }
+                if len < 4 {
+                    return Err(invalid_frame("snappy chunk is too short"));
+                }
+                let checksum = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                let data = if header[0] == SNAPPY_COMPRESSED {
+                    let n = decoder.decompress(&chunk[4..], &mut output)?;
+                    &output[..n]
+                } else {
+                    &chunk[4..]
+                };
+                if snappy_checksum(data) != checksum {
+                    return Err(invalid_frame("snappy checksum mismatch"));
+                }
+                writer.write_all(data).await?;
+                size += data.len() as u64;
+            }
+            // reserved unskippable chunks
+            0x02..=0x7f => return Err(invalid_frame("snappy chunk type is unsupported")),
+            // padding and reserved skippable chunks
+            _ => {}
+        }
+    }
+    Ok(size)
+}
+
+pub(crate) async fn lz4_encode<R, W>(reader: &mut R, writer: &mut W) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut input = vec![0; BLOCK_SIZE];
+    let mut output = vec![0; block::get_maximum_output_size(BLOCK_SIZE)];
+    let descriptor = [LZ4_FLG, LZ4_BD];
+    let header_checksum = (XxHash32::oneshot(0, &descriptor) >> 8) as u8;
+    writer.write_all(LZ4_MAGIC).await?;
+    writer.write_all(&descriptor).await?;
+    writer.write_all(&[header_checksum]).await?;
+    let mut written = LZ4_MAGIC.len() as u64 + 3;
+    loop {
+        let n = read_full(reader, &mut input).await?;
+        if n == 0 {
+            break;
+        }
+        let size = block::compress_into(&input[..n], &mut output)?;
+        let (len, body) = if size < n {
+            (size as u32, &output[..size])
+        } else {
+            (n as u32 | LZ4_UNCOMPRESSED_BIT, &input[..n])
+        };
+        writer.write_all(&len.to_le_bytes()).await?;
+        writer.write_all(body).await?;
+        written += 4 + body.len() as u64;
+    }
+    // end mark
+    writer.write_all(&0u32.to_le_bytes()).await?;
+    Ok(written + 4)
+}
+
+pub(crate) async fn lz4_decode<R, W>(reader: &mut R, writer: &mut W) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic).await?;
+    if magic != LZ4_MAGIC {
+        return Err(invalid_frame("lz4 magic number is invalid"));
+    }
+    let mut descriptor = vec![0; 2];
+    reader.read_exact(&mut descriptor).await?;
+    let flg = descriptor[0];
+    let bd = descriptor[1];
+    if flg >> 6 != 0x01 {
+        return Err(invalid_frame("lz4 frame version is unsupported"));
+    }
+    let independent = flg & 0x20 != 0;
+    let block_checksum = flg & 0x10 != 0;
+    let content_size = flg & 0x08 != 0;
+    let content_checksum = flg & 0x04 != 0;
+    let dict_id = flg & 0x01 != 0;
+    let max_block_size = match (bd >> 4) & 0x07 {
+        4 => 64 * 1024,
+        5 => 256 * 1024,
+        6 => 1024 * 1024,
+        7 => 4 * 1024 * 1024,
+        _ => return Err(invalid_frame("lz4 block max size is invalid")),
+    };
+    let optional = if content_size { 8 } else { 0 } + if dict_id { 4 } else { 0 };
+    descriptor.resize(2 + optional, 0);
+    reader.read_exact(&mut descriptor[2..]).await?;
+    if reader.read_u8().await? != (XxHash32::oneshot(0, &descriptor) >> 8) as u8 {
+        return Err(invalid_frame("lz4 header checksum mismatch"));
+    }
+
+    let mut hasher = XxHash32::with_seed(0);
+    let mut input = vec![];
+    let mut output = vec![0; max_block_size];
+    // linked blocks reference up to 64KB of previous data
+    let mut dict = vec![];
+    let mut size = 0;
+    loop {
+        let len = reader.read_u32_le().await?;
+        if len == 0 {
+            break;
+        }
+        let uncompressed = len & LZ4_UNCOMPRESSED_BIT != 0;
+        let len = (len & !LZ4_UNCOMPRESSED_BIT) as usize;
+        if len > max_block_size {
+            return Err(invalid_frame("lz4 block is too large"));
+        }
+        input.resize(len, 0);
+        reader.read_exact(&mut input).await?;
+        if block_checksum && reader.read_u32_le().await? != XxHash32::oneshot(0, &input) {
+            return Err(invalid_frame("lz4 block checksum mismatch"));
+        }
+        let data = if uncompressed {
+            &input[..]
+        } else {
+            let n = if independent {
+                block::decompress_into(&input, &mut output)?
+            } else {
+                block::decompress_into_with_dict(&input, &mut output, &dict)?
+            };
+            &output[..n]
+        };
+        writer.write_all(data).await?;
+        if content_checksum {
+            hasher.write(data);
+        }
+        if !independent {
+            dict.extend_from_slice(data);
+            if dict.len() > BLOCK_SIZE {
+                dict.drain(..dict.len() - BLOCK_SIZE);
+            }
+        }
+        size += data.len() as u64;
+    }
+    if content_checksum && reader.read_u32_le().await? != hasher.finish_32() {
+        return Err(invalid_frame("lz4 content checksum mismatch"));
+    }
+    Ok(size)
+}
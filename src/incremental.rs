@@ -0,0 +1,208 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Incremental archives store the files changed since a base archive,
+// the deleted entries are recorded as tombstones in the manifest.
+// A chain of archives is restored from the full archive to any incremental one.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+use tokio::fs;
+use tokio::fs::File;
+use tracing::info;
+
+use super::archiver::{
+    SourceEntry, SourceKind, UnarchiveParams, list_with_key, safe_join, unarchive,
+};
+use super::crypto::{KeySource, open_manifest};
+use super::error::Error;
+use super::manifest::{HashReader, Manifest, ManifestFile, manifest_mtime, read_manifest};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Incremental {
+    /// Blake3 checksum of the manifest of base archive
+    pub base: String,
+    /// Files unchanged since the base archive, they are stored in the previous archives
+    pub unchanged: Vec<ManifestFile>,
+    /// Tombstones of the entries deleted since the base archive
+    pub deleted: Vec<String>,
+}
+
+fn invalid(message: String) -> Error {
+    Error::InvalidIncremental { message }
+}
+
+/// Read the manifest of base archive, or the manifest json exported from it.
+/// Returns the opened manifest, its checksum and the paths of stored entries,
+/// which are unknown for the manifest json.
+async fn read_base(
+    base: &str,
+    key: Option<&KeySource>,
+) -> Result<(Manifest, String, Vec<String>), Error> {
+    if !Path::new(base).exists() {
+        return Err(Error::PathNotExists {
+            path: base.to_string(),
+        });
+    }
+    let (mut manifest, stored) = if base.ends_with(".json") {
+        let data = fs::read(base).await?;
+        (Manifest::from_json(data.trim_ascii_end())?, vec![])
+    } else {
+        let manifest = read_manifest(base)
+            .await?
+            .ok_or_else(|| invalid(format!("base {base} has no manifest")))?;
+        let stored = list_with_key(base, key)
+            .await?
+            .into_iter()
+            .map(|entry| entry.path.trim_end_matches('/').to_string())
+            .collect();
+        (manifest, stored)
+    };
+    let checksum = manifest.checksum()?;
+    open_manifest(Some(&mut manifest), key)?;
+    Ok((manifest, checksum, stored))
+}
+
+/// The file is unchanged if its size and mtime are the same as the record,
+/// or its content is the same if only the mtime is changed.
+async fn is_unchanged(entry: &SourceEntry, file: &ManifestFile) -> Result<bool, Error> {
+    if file.size != entry.meta.len() {
+        return Ok(false);
+    }
+    if file.mtime.as_deref() == Some(manifest_mtime(&entry.meta).as_str()) {
+        return Ok(true);
+    }
+    let mut r = HashReader::new(File::open(&entry.path).await?);
+    tokio::io::copy(&mut r, &mut tokio::io::sink()).await?;
+    Ok(r.checksum() == file.checksum)
+}
+
+/// Keep the source entries changed since the base archive, the directories
+/// and links are always kept. Returns them with the incremental part of manifest.
+pub(crate) async fn changed_entries(
+    entries: Vec<SourceEntry>,
+    base: &str,
+    key: Option<&KeySource>,
+) -> Result<(Vec<SourceEntry>, Incremental), Error> {
+    let (manifest, checksum, stored) = read_base(base, key).await?;
+    let snapshot = manifest.snapshot();
+    let mut paths = HashSet::new();
+    let mut changed = vec![];
+    let mut unchanged = vec![];
+    for entry in entries {
+        let path = entry.name.to_string_lossy().to_string();
+        if let SourceKind::File = entry.kind
+            && let Some(file) = snapshot.get(path.as_str())
+            && is_unchanged(&entry, file).await?
+        {
+            unchanged.push(ManifestFile {
+                mtime: Some(manifest_mtime(&entry.meta)),
+                ..(*file).clone()
+            });
+        } else {
+            changed.push(entry);
+        }
+        paths.insert(path);
+    }
+    let mut deleted: Vec<String> = snapshot
+        .keys()
+        .map(|path| path.to_string())
+        .chain(stored)
+        .filter(|path| !paths.contains(path))
+        .collect();
+    deleted.sort();
+    deleted.dedup();
+    info!(
+        base,
+        changed = changed.len(),
+        unchanged = unchanged.len(),
+        deleted = deleted.len(),
+        "incremental archive"
+    );
+    Ok((
+        changed,
+        Incremental {
+            base: checksum,
+            unchanged,
+            deleted,
+        },
+    ))
+}
+
+/// Remove the entry of tombstone from the output directory.
+async fn remove_entry(output: &Path, path: &str, unsafe_paths: bool) -> Result<(), Error> {
+    let file_path = safe_join(output, Path::new(path), unsafe_paths)?;
+    let result = match fs::symlink_metadata(&file_path).await {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(&file_path).await,
+        Ok(_) => fs::remove_file(&file_path).await,
+        Err(err) => Err(err),
+    };
+    match result {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Restore the chain of archives to the target directory of params. The chain
+/// starts from a full archive and each incremental archive is based on the
+/// previous one, the tombstones are removed before its files are unarchived.
+/// The chain can end at any archive to restore its point in time.
+pub async fn restore(chain: &[String], params: UnarchiveParams) -> Result<(), Error> {
+    if chain.is_empty() || params.target.is_empty() {
+        return Err(Error::InvalidArg {
+            path: params.target,
+        });
+    }
+    // the chain is checked before anything is restored
+    let mut tombstones = vec![];
+    let mut previous: Option<String> = None;
+    for source in chain {
+        let mut manifest = read_manifest(source)
+            .await?
+            .ok_or_else(|| invalid(format!("{source} has no manifest")))?;
+        let checksum = manifest.checksum()?;
+        open_manifest(Some(&mut manifest), params.key.as_ref())?;
+        match (manifest.incremental, previous.as_ref()) {
+            (None, None) => tombstones.push(vec![]),
+            (Some(incremental), Some(previous)) if incremental.base == *previous => {
+                tombstones.push(incremental.deleted);
+            }
+            (Some(_), None) => {
+                return Err(invalid(format!("{source} is not a full archive")));
+            }
+            (None, Some(_)) => {
+                return Err(invalid(format!("{source} is not an incremental archive")));
+            }
+            (Some(_), Some(_)) => {
+                return Err(invalid(format!(
+                    "{source} is not based on the previous archive"
+                )));
+            }
+        }
+        previous = Some(checksum);
+    }
+    let output = Path::new(&params.target);
+    for (source, deleted) in chain.iter().zip(tombstones) {
+        for path in deleted.iter() {
+            remove_entry(output, path, params.unsafe_paths).await?;
+        }
+        unarchive(UnarchiveParams {
+            source: source.clone(),
+            ..params.clone()
+        })
+        .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,387 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The index is the last entry of archive, it maps the paths to the offsets
+// of their entries. Its data ends with a fixed size footer which records the
+// offset of the index header, so the footer is found right before the two
+// zero blocks of the end of archive, and the archive is still a plain tar.
+
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_stream::StreamExt;
+use tokio_tar::{Builder, EntryType, Header};
+
+use super::archiver::{decrypt_name, write_entry};
+use super::codec::{Compression, Writer};
+use super::crypto::{Crypter, KeySource, open_manifest};
+use super::dedup::{ChunkStore, is_chunk, resolve_codec};
+use super::error::Error;
+use super::limit::Limits;
+use super::manifest::{
+    MANIFEST_PATH, open_archive, open_archive_at, read_manifest, resolve_compression,
+};
+use super::pax::read_pax;
+use super::sparse::SparseMap;
+
+/// Path of the index entry, it is the last entry of archive.
+pub const INDEX_PATH: &str = ".archiver-index.json";
+pub const INDEX_VERSION: u32 = 1;
+const INDEX_MAGIC: &str = "ARCHIVER-INDEX ";
+// newline, magic, offset of 20 digits and newline
+const FOOTER_SIZE: usize = INDEX_MAGIC.len() + 22;
+const BLOCK_SIZE: u64 = 512;
+// the end of archive is two zero blocks
+const END_SIZE: u64 = 2 * BLOCK_SIZE;
+
+/// Location of the entry in the archive.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// Path stored in the tar header, it is encrypted if the names are encrypted
+    pub path: String,
+    /// Offset of the first header of entry, including the PAX
+    /// and GNU long name headers
+    pub header: u64,
+    /// Offset of the stored data
+    pub offset: u64,
+    /// Size of the stored data
+    pub length: u64,
+    pub codec: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ArchiveIndex {
+    pub version: u32,
+    /// Offset of the manifest header, it is none if the manifest is the
+    /// first entry. The manifest is rewritten before the index when
+    /// files are appended, as its reserved space is not enough.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<u64>,
+    pub entries: Vec<IndexEntry>,
+}
+
+impl ArchiveIndex {
+    pub fn to_json(&self) -> Result<Vec<u8>, Error> {
+        serde_json::to_vec(self).map_err(|err| Error::Json { source: err })
+    }
+    pub fn from_json(data: &[u8]) -> Result<Self, Error> {
+        serde_json::from_slice(data).map_err(|err| Error::Json { source: err })
+    }
+    /// The entries must be compressed with the compression of archive,
+    /// as they are copied or decoded with it.
+    pub(crate) fn check_codec(&self, compression: &Compression) -> Result<(), Error> {
+        if let Some(entry) = self
+            .entries
+            .iter()
+            .find(|entry| entry.codec != compression.to_string())
+        {
+            return Err(Error::CompressionMismatch {
+                expected: entry.codec.clone(),
+                actual: compression.to_string(),
+            });
+        }
+        Ok(())
+    }
+    /// Find the entry of the path, the last one wins if the path
+    /// is archived more than once.
+    pub(crate) fn find(
+        &self,
+        path: &str,
+        crypter: Option<&Crypter>,
+    ) -> Result<Option<&IndexEntry>, Error> {
+        for entry in self.entries.iter().rev() {
+            if decrypt_name(crypter, Path::new(&entry.path))?.to_string_lossy() == path {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The manifest and index entries are not files of archive.
+pub(crate) fn is_metadata(path: &str) -> bool {
+    path == MANIFEST_PATH || path == INDEX_PATH
+}
+
+/// Position of the archive file, the buffered data is flushed first.
+pub(crate) async fn stream_position(a: &mut Builder<File>) -> Result<u64, Error> {
+    let w = a.get_mut();
+    w.flush().await?;
+    Ok(w.stream_position().await?)
+}
+
+/// Append the entry to the index, it starts from the header offset and
+/// ends at the current position with the padded data of length.
+pub(crate) async fn index_entry(
+    a: &mut Builder<File>,
+    index: &mut ArchiveIndex,
+    path: &Path,
+    header: u64,
+    length: u64,
+    compression: &Compression,
+) -> Result<(), Error> {
+    let end = stream_position(a).await?;
+    index.entries.push(IndexEntry {
+        path: path.to_string_lossy().to_string(),
+        header,
+        offset: end - length.div_ceil(BLOCK_SIZE) * BLOCK_SIZE,
+        length,
+        codec: compression.to_string(),
+    });
+    Ok(())
+}
+
+fn footer(offset: u64) -> String {
+    format!("\n{INDEX_MAGIC}{offset:020}\n")
+}
+
+/// Append the index entry, the json is padded with spaces so that
+/// the footer is the end of the last data block.
+pub(crate) async fn append_index(a: &mut Builder<File>, index: &ArchiveIndex) -> Result<(), Error> {
+    let offset = stream_position(a).await?;
+    let mut data = index.to_json()?;
+    let size = (data.len() + FOOTER_SIZE).div_ceil(BLOCK_SIZE as usize) * BLOCK_SIZE as usize;
+    data.resize(size - FOOTER_SIZE, b' ');
+    data.extend_from_slice(footer(offset).as_bytes());
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    a.append_data(&mut header, INDEX_PATH, &data[..]).await?;
+    Ok(())
+}
+
+/// Offset of the index header recorded by the footer,
+/// returns none if the archive has no index.
+pub(crate) fn index_offset(path: &str) -> Result<Option<u64>, Error> {
+    let mut file = std::fs::File::open(path)?;
+    let size = file.metadata()?.len();
+    let tail = FOOTER_SIZE as u64 + END_SIZE;
+    if size < tail + BLOCK_SIZE {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(size - tail))?;
+    let mut buf = vec![0; tail as usize];
+    file.read_exact(&mut buf)?;
+    if buf[FOOTER_SIZE..].iter().any(|b| *b != 0) {
+        return Ok(None);
+    }
+    let footer = String::from_utf8_lossy(&buf[..FOOTER_SIZE]);
+    let offset = footer
+        .strip_prefix('\n')
+        .and_then(|value| value.strip_prefix(INDEX_MAGIC))
+        .and_then(|value| value.strip_suffix('\n'))
+        .and_then(|value| value.parse::<u64>().ok());
+    Ok(offset.filter(|offset| offset % BLOCK_SIZE == 0 && *offset < size - tail))
+}
+
+/// Read the index from the end of archive, returns none if the archive
+/// is created without index or it is modified by other tools.
+pub async fn read_index(path: &str) -> Result<Option<ArchiveIndex>, Error> {
+    let Some(offset) = index_offset(path)? else {
+        return Ok(None);
+    };
+    let mut r = open_archive_at(path, offset)?;
+    let mut entries = r.entries()?;
+    let Some(file) = entries.next().await else {
+        return Ok(None);
+    };
+    let mut f = file?;
+    if f.path()?.to_string_lossy() != INDEX_PATH {
+        return Ok(None);
+    }
+    let mut data = vec![];
+    f.read_to_end(&mut data).await?;
+    if !data.ends_with(footer(offset).as_bytes()) {
+        return Ok(None);
+    }
+    data.truncate(data.len() - FOOTER_SIZE);
+    let index = ArchiveIndex::from_json(data.trim_ascii_end())?;
+    // the index of newer version is not used
+    if index.version > INDEX_VERSION {
+        return Ok(None);
+    }
+    Ok(Some(index))
+}
+
+/// Build the index by scanning the raw entries of archive without index,
+/// returns it with the offset of the end of entries.
+pub(crate) async fn scan_index(
+    target: &str,
+    compression: &Compression,
+) -> Result<(ArchiveIndex, u64), Error> {
+    let mut r = open_archive(target)?;
+    let mut entries = r.entries_raw()?;
+    let mut index = ArchiveIndex {
+        version: INDEX_VERSION,
+        ..Default::default()
+    };
+    let mut end = 0;
+    // offset of the first header and the long path of the pending entry
+    let mut start = None;
+    let mut long_path = None;
+    while let Some(file) = entries.next().await {
+        let mut f = file?;
+        let length = f.header().size()?;
+        let offset = f.raw_file_position();
+        end = offset + length.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        let header = *start.get_or_insert(f.raw_header_position());
+        match f.header().entry_type() {
+            EntryType::GNULongName => {
+                let mut data = vec![];
+                f.read_to_end(&mut data).await?;
+                // the long name ends with nul
+                data.truncate(data.iter().position(|b| *b == 0).unwrap_or(data.len()));
+                long_path = Some(data);
+                continue;
+            }
+            EntryType::XHeader => {
+                if let Some(path) = read_pax(&mut f).await?.remove("path") {
+                    long_path = Some(path);
+                }
+                continue;
+            }
+            EntryType::GNULongLink => continue,
+            EntryType::XGlobalHeader => {
+                start = None;
+                continue;
+            }
+            _ => {}
+        }
+        start = None;
+        let path = long_path.take().unwrap_or_else(|| f.path_bytes().to_vec());
+        let path = String::from_utf8_lossy(&path).to_string();
+        if is_metadata(&path) {
+            continue;
+        }
+        index.entries.push(IndexEntry {
+            path,
+            header,
+            offset,
+            length,
+            codec: compression.to_string(),
+        });
+    }
+    Ok((index, end))
+}
+
+/// Offset of the header to read the entry of path from, it is none if the
+/// path is not in the index, and the archive is scanned from start without index.
+pub(crate) fn header_offset(
+    index: Option<&ArchiveIndex>,
+    path: &str,
+    crypter: Option<&Crypter>,
+) -> Result<Option<u64>, Error> {
+    match index {
+        Some(index) => Ok(index.find(path, crypter)?.map(|entry| entry.header)),
+        None => Ok(Some(0)),
+    }
+}
+
+/// Seekable reader of archive, the file is read from its entry
+/// found by the index, or by scanning the archive if it has no index.
+pub struct IndexedReader {
+    target: String,
+    compression: Compression,
+    crypter: Option<Crypter>,
+    index: Option<ArchiveIndex>,
+    store: Option<Arc<ChunkStore>>,
+}
+
+impl IndexedReader {
+    pub async fn open(
+        target: &str,
+        compression: Option<Compression>,
+        key: Option<&KeySource>,
+    ) -> Result<Self, Error> {
+        if target.is_empty() {
+            return Err(Error::InvalidArg {
+                path: target.to_string(),
+            });
+        }
+        let mut manifest = read_manifest(target).await?;
+        let compression = resolve_compression(target, compression, manifest.as_ref())?;
+        let crypter = open_manifest(manifest.as_mut(), key)?;
+        let index = read_index(target).await?;
+        Ok(Self {
+            target: target.to_string(),
+            compression,
+            crypter,
+            store: index
+                .as_ref()
+                .map(|index| ChunkStore::new(target, index).map(Arc::new))
+                .transpose()?,
+            index,
+        })
+    }
+    /// Index of archive, it is none for archives without index.
+    pub fn index(&self) -> Option<&ArchiveIndex> {
+        self.index.as_ref()
+    }
+    /// Decode the file of the path to the writer, returns its size,
+    /// or none if the file is not found.
+    pub async fn read_file(
+        &self,
+        path: &str,
+        writer: &mut Writer<'_>,
+    ) -> Result<Option<u64>, Error> {
+        let Some(offset) = header_offset(self.index.as_ref(), path, self.crypter.as_ref())? else {
+            return Ok(None);
+        };
+        let mut r = open_archive_at(&self.target, offset)?;
+        let mut entries = r.entries()?;
+        while let Some(file) = entries.next().await {
+            let mut f = file?;
+            let name = f.path()?.to_string_lossy().to_string();
+            if is_metadata(&name) || is_chunk(&name) {
+                continue;
+            }
+            let name = decrypt_name(self.crypter.as_ref(), Path::new(&name))?;
+            if name.to_string_lossy() != path {
+                // the entry at the offset must be the file
+                if self.index.is_some() {
+                    return Err(Error::InvalidIndex {
+                        message: format!("{path} is not found at {offset}"),
+                    });
+                }
+                continue;
+            }
+            if !f.header().entry_type().is_file() {
+                return Err(Error::InvalidArg {
+                    path: path.to_string(),
+                });
+            }
+            let pax = read_pax(&mut f).await?;
+            let sparse = SparseMap::from_records(&pax)?;
+            let mut store = self.store.clone();
+            let size = f.header().size()?;
+            let (codec, compressed) = resolve_codec(
+                &pax,
+                path,
+                &mut f,
+                &mut store,
+                &self.target,
+                &self.compression,
+                self.crypter.as_ref(),
+            )
+            .await?;
+            let limits = Limits::default().entry(path, compressed.unwrap_or(size));
+            let size = write_entry(codec.as_ref(), &mut f, writer, sparse, limits).await?;
+            return Ok(Some(size));
+        }
+        Ok(None)
+    }
+}
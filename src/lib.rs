@@ -13,9 +13,29 @@
 // limitations under the License.
 
 mod archiver;
+mod codec;
 mod compression;
+mod crypto;
+mod dedup;
 mod error;
+mod frame;
+mod incremental;
+mod index;
+mod limit;
+mod manifest;
+mod pax;
+mod signature;
+mod sparse;
+mod xattrs;
 
 pub use archiver::*;
+pub use codec::*;
 pub use compression::*;
+pub use crypto::*;
+pub use dedup::*;
 pub use error::*;
+pub use incremental::*;
+pub use index::*;
+pub use limit::*;
+pub use manifest::*;
+pub use signature::*;
@@ -0,0 +1,206 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::AsyncWrite;
+
+use super::error::Error;
+
+// small entries have high ratio legitimately, e.g. a file of zeros,
+// so the ratio is checked once the entry exceeds this size
+const RATIO_CHECK_SIZE: u64 = 1024 * 1024;
+
+/// Limit of extraction which is exceeded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    /// Max decompressed size of each entry
+    EntrySize(u64),
+    /// Max decompressed size of all entries
+    TotalSize(u64),
+    /// Max ratio of decompressed size to compressed size of each entry
+    Ratio(f64),
+    /// Max count of entries
+    Entries(usize),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::EntrySize(size) => write!(f, "max entry size {}", bytesize::ByteSize(*size)),
+            Limit::TotalSize(size) => write!(f, "max total size {}", bytesize::ByteSize(*size)),
+            Limit::Ratio(ratio) => write!(f, "max ratio {ratio}"),
+            Limit::Entries(count) => write!(f, "max entries {count}"),
+        }
+    }
+}
+
+/// Limits of the decompressed size, the total size is shared by workers.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Limits {
+    pub(crate) entry_size: Option<u64>,
+    pub(crate) total_size: Option<u64>,
+    pub(crate) ratio: Option<f64>,
+    pub(crate) total: Arc<AtomicU64>,
+}
+
+impl Limits {
+    /// Limits of the entry whose compressed size is `compressed`.
+    pub(crate) fn entry(&self, path: &str, compressed: u64) -> EntryLimits {
+        EntryLimits {
+            limits: self.clone(),
+            path: path.to_string(),
+            compressed,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct EntryLimits {
+    limits: Limits,
+    path: String,
+    compressed: u64,
+}
+
+impl EntryLimits {
+    /// Check the size of sparse file before decoding, its holes are not
+    /// written through the limit writer, so they are added to the total.
+    pub(crate) fn reserve_holes(&self, size: u64, holes: u64) -> Result<(), Error> {
+        let limits = &self.limits;
+        let limit = if let Some(max) = limits.entry_size
+            && size > max
+        {
+            Some(Limit::EntrySize(max))
+        } else if let Some(max) = limits.total_size
+            && limits.total.load(Ordering::Relaxed).saturating_add(size) > max
+        {
+            Some(Limit::TotalSize(max))
+        } else {
+            None
+        };
+        if let Some(limit) = limit {
+            return Err(Error::LimitExceeded {
+                path: self.path.clone(),
+                limit,
+            });
+        }
+        limits.total.fetch_add(holes, Ordering::Relaxed);
+        Ok(())
+    }
+    pub(crate) fn writer<W>(self, inner: W) -> LimitWriter<W> {
+        LimitWriter {
+            inner,
+            entry: self,
+            size: 0,
+            exceeded: None,
+        }
+    }
+}
+
+/// Writer fails if the written size exceeds the limits.
+pub(crate) struct LimitWriter<W> {
+    inner: W,
+    entry: EntryLimits,
+    size: u64,
+    exceeded: Option<Limit>,
+}
+
+impl<W> LimitWriter<W> {
+    pub(crate) fn into_inner(self) -> W {
+        self.inner
+    }
+    /// Max size which can be written, the codec decoding in memory
+    /// rejects the block larger than it before allocating.
+    pub(crate) fn max_size(&self) -> Option<u64> {
+        let limits = &self.entry.limits;
+        let entry = limits.entry_size.map(|max| max.saturating_sub(self.size));
+        let total = limits
+            .total_size
+            .map(|max| max.saturating_sub(limits.total.load(Ordering::Relaxed)));
+        let ratio = limits.ratio.map(|ratio| {
+            let max = (self.entry.compressed.max(1) as f64 * ratio) as u64;
+            max.max(RATIO_CHECK_SIZE).saturating_sub(self.size)
+        });
+        [entry, total, ratio].into_iter().flatten().min()
+    }
+    /// Returns the limit error instead of the io error of the writer.
+    pub(crate) fn check<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        let exceeded = match (self.exceeded, &result) {
+            (Some(limit), _) => Some(limit),
+            (None, Err(Error::BlockTooLarge { size })) => {
+                self.exceed(self.size.saturating_add(*size))
+            }
+            _ => None,
+        };
+        match exceeded {
+            Some(limit) => Err(Error::LimitExceeded {
+                path: self.entry.path.clone(),
+                limit,
+            }),
+            None => result,
+        }
+    }
+    fn exceed(&self, size: u64) -> Option<Limit> {
+        let limits = &self.entry.limits;
+        if let Some(max) = limits.entry_size
+            && size > max
+        {
+            return Some(Limit::EntrySize(max));
+        }
+        if let Some(ratio) = limits.ratio
+            && size > RATIO_CHECK_SIZE
+            && size as f64 > self.entry.compressed.max(1) as f64 * ratio
+        {
+            return Some(Limit::Ratio(ratio));
+        }
+        if let Some(max) = limits.total_size
+            && limits.total.load(Ordering::Relaxed) + size - self.size > max
+        {
+            return Some(Limit::TotalSize(max));
+        }
+        None
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for LimitWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let exceeded = self.exceed(self.size + buf.len() as u64);
+        if exceeded.is_some() {
+            self.exceeded = exceeded;
+            return Poll::Ready(Err(std::io::Error::other("limit exceeded")));
+        }
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(size)) = poll {
+            self.size += size as u64;
+            self.entry
+                .limits
+                .total
+                .fetch_add(size as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
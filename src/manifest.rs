@@ -0,0 +1,403 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio_stream::StreamExt;
+use tokio_tar::Archive;
+
+use super::archiver::check_appending;
+use super::codec::Compression;
+use super::crypto::{Crypter, ManifestEncryption, sealed_len};
+use super::error::Error;
+use super::incremental::Incremental;
+use super::index::read_index;
+use super::pax::format_time;
+use super::sparse::SparseMap;
+
+/// Path of the manifest entry, it is the first entry of archive
+/// and stored without compression.
+pub const MANIFEST_PATH: &str = ".archiver-manifest.json";
+pub const MANIFEST_VERSION: u32 = 1;
+// max length of the mtime, e.g. -9223372036854775807.999999999
+const MTIME_SIZE: usize = 30;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ManifestFile {
+    pub path: String,
+    /// Original size of the file
+    pub size: u64,
+    pub mode: u32,
+    /// Blake3 checksum of the original file
+    pub checksum: String,
+    /// Modification time of the file as PAX time, it is compared
+    /// to find the changed files of incremental archive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub compression: String,
+    pub level: i32,
+    pub files: Vec<ManifestFile>,
+    /// Parameters of encryption, it is none if the archive is not encrypted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<ManifestEncryption>,
+    /// The files are sealed as base64 if the archive is encrypted,
+    /// as their paths and checksums reveal the content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<String>,
+    /// Base and tombstones of the incremental archive, it is none for a full archive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incremental: Option<Incremental>,
+}
+
+/// Sealed content of the encrypted manifest, the incremental part
+/// is sealed with the files as it reveals their paths too.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Sealed {
+    Files(Vec<ManifestFile>),
+    Incremental {
+        files: Vec<ManifestFile>,
+        incremental: Incremental,
+    },
+}
+
+impl Manifest {
+    pub fn to_json(&self) -> Result<Vec<u8>, Error> {
+        serde_json::to_vec(self).map_err(|err| Error::Json { source: err })
+    }
+    pub fn from_json(data: &[u8]) -> Result<Self, Error> {
+        serde_json::from_slice(data).map_err(|err| Error::Json { source: err })
+    }
+    /// Upper bound of the json size for the paths, the manifest entry is
+    /// reserved with this size before the files are compressed.
+    pub(crate) fn reserved_size(&self, paths: &[String]) -> Result<u64, Error> {
+        let mut placeholder = Manifest {
+            files: paths
+                .iter()
+                .map(|path| ManifestFile {
+                    path: path.clone(),
+                    size: u64::MAX,
+                    mode: u32::MAX,
+                    checksum: "0".repeat(blake3::OUT_LEN * 2),
+                    mtime: Some("0".repeat(MTIME_SIZE)),
+                })
+                .collect(),
+            ..self.clone()
+        };
+        if placeholder.encryption.is_some() {
+            let data = placeholder.take_sealed()?;
+            placeholder.sealed = Some("0".repeat(sealed_len(data.len())));
+        }
+        Ok(placeholder.to_json()?.len() as u64)
+    }
+    /// Take the files and incremental part as the json to seal.
+    fn take_sealed(&mut self) -> Result<Vec<u8>, Error> {
+        let files = std::mem::take(&mut self.files);
+        let sealed = match self.incremental.take() {
+            Some(incremental) => Sealed::Incremental { files, incremental },
+            None => Sealed::Files(files),
+        };
+        serde_json::to_vec(&sealed).map_err(|err| Error::Json { source: err })
+    }
+    /// Seal the files with the crypter of archive.
+    pub(crate) fn seal(&mut self, crypter: &Crypter) -> Result<(), Error> {
+        let data = self.take_sealed()?;
+        self.sealed = Some(STANDARD.encode(crypter.seal_message(&data)?));
+        Ok(())
+    }
+    /// Open the sealed files with the crypter of archive.
+    pub(crate) fn unseal(&mut self, crypter: &Crypter) -> Result<(), Error> {
+        let Some(sealed) = self.sealed.take() else {
+            return Ok(());
+        };
+        let data = STANDARD.decode(sealed).map_err(|_| Error::Decrypt {
+            message: "invalid manifest".to_string(),
+        })?;
+        match serde_json::from_slice(&crypter.open_message(&data)?)
+            .map_err(|err| Error::Json { source: err })?
+        {
+            Sealed::Files(files) => self.files = files,
+            Sealed::Incremental { files, incremental } => {
+                self.files = files;
+                self.incremental = Some(incremental);
+            }
+        }
+        Ok(())
+    }
+    /// Keep the last record of each path, the appended files
+    /// replace the records of the same paths.
+    pub(crate) fn retain_latest(&mut self) {
+        let mut paths = HashSet::new();
+        self.files.reverse();
+        self.files.retain(|file| paths.insert(file.path.clone()));
+        self.files.reverse();
+    }
+    pub fn file(&self, path: &str) -> Option<&ManifestFile> {
+        self.files.iter().find(|file| file.path == path)
+    }
+    /// Files at the point in time of archive, including the unchanged files
+    /// of incremental archive which are stored in the previous archives.
+    pub fn snapshot(&self) -> HashMap<&str, &ManifestFile> {
+        let unchanged = self
+            .incremental
+            .iter()
+            .flat_map(|incremental| incremental.unchanged.iter());
+        unchanged
+            .chain(self.files.iter())
+            .map(|file| (file.path.as_str(), file))
+            .collect()
+    }
+    /// Blake3 checksum of the manifest json, the incremental
+    /// archive refers to its base with it.
+    pub fn checksum(&self) -> Result<String, Error> {
+        Ok(blake3::hash(&self.to_json()?).to_hex().to_string())
+    }
+}
+
+/// Modification time of the file recorded in the manifest.
+pub(crate) fn manifest_mtime(meta: &std::fs::Metadata) -> String {
+    String::from_utf8_lossy(&format_time(FileTime::from_last_modification_time(meta))).to_string()
+}
+
+/// Reader of the archive file, it reads the file with blocking io.
+/// tokio-tar loses the GNU long name and PAX extension entries
+/// if the reader is pending while they are read, so the reader must be always ready.
+pub(crate) struct ArchiveReader(std::io::BufReader<std::fs::File>);
+
+impl AsyncRead for ArchiveReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let size = self.0.read(buf.initialize_unfilled())?;
+        buf.advance(size);
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub(crate) fn open_archive(path: &str) -> Result<Archive<ArchiveReader>, Error> {
+    let file = std::fs::File::open(path)?;
+    Ok(Archive::new(ArchiveReader(std::io::BufReader::new(file))))
+}
+
+/// Open the archive from the header at the offset, the entries
+/// before it are not read.
+pub(crate) fn open_archive_at(path: &str, offset: u64) -> Result<Archive<ArchiveReader>, Error> {
+    let mut file = std::fs::File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    Ok(Archive::new(ArchiveReader(std::io::BufReader::new(file))))
+}
+
+/// Read the manifest from the first entry of archive, or the entry recorded
+/// by the index if files are appended. Returns none if the archive is created
+/// by old version without manifest. It fails if the archive is being appended
+/// or its append is interrupted.
+pub async fn read_manifest(path: &str) -> Result<Option<Manifest>, Error> {
+    check_appending(path)?;
+    let offset = read_index(path)
+        .await?
+        .and_then(|index| index.manifest)
+        .unwrap_or_default();
+    let mut r = open_archive_at(path, offset)?;
+    let mut entries = r.entries()?;
+    let Some(file) = entries.next().await else {
+        return Ok(None);
+    };
+    let mut f = file?;
+    if f.path()?.to_string_lossy() != MANIFEST_PATH {
+        return Ok(None);
+    }
+    let mut data = vec![];
+    f.read_to_end(&mut data).await?;
+    Ok(Some(Manifest::from_json(data.trim_ascii_end())?))
+}
+
+/// Resolve the compression of archive, the explicit compression and the
+/// compression inferred from file name must match the manifest.
+pub(crate) fn resolve_compression(
+    path: &str,
+    compression: Option<Compression>,
+    manifest: Option<&Manifest>,
+) -> Result<Compression, Error> {
+    let Some(manifest) = manifest else {
+        return match compression {
+            Some(compression) => Ok(compression),
+            None => Compression::from_path(path),
+        };
+    };
+    let expected: Compression = manifest.compression.parse()?;
+    let actual = match compression {
+        Some(compression) => Some(compression),
+        // the file may be renamed, so only a valid compression is compared
+        None => Compression::from_path(path).ok(),
+    };
+    if let Some(actual) = actual
+        && actual != expected
+    {
+        return Err(Error::CompressionMismatch {
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        });
+    }
+    Ok(expected)
+}
+
+/// Reader calculates the size and blake3 checksum of read data.
+pub(crate) struct HashReader<R> {
+    inner: R,
+    hasher: blake3::Hasher,
+    size: u64,
+}
+
+impl<R> HashReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: blake3::Hasher::new(),
+            size: 0,
+        }
+    }
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+    pub(crate) fn checksum(&self) -> String {
+        self.hasher.finalize().to_hex().to_string()
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let data = &buf.filled()[before..];
+            self.hasher.update(data);
+            self.size += data.len() as u64;
+        }
+        poll
+    }
+}
+
+/// Writer calculates the size and blake3 checksum of written data
+/// and drops it, the holes of sparse file are hashed as zeros.
+pub(crate) struct HashWriter {
+    hasher: blake3::Hasher,
+    size: u64,
+    sparse: Option<SparseMap>,
+    index: usize,
+    // written size of current region
+    written: u64,
+}
+
+impl HashWriter {
+    pub(crate) fn new(sparse: Option<SparseMap>) -> Self {
+        Self {
+            hasher: blake3::Hasher::new(),
+            size: 0,
+            sparse,
+            index: 0,
+            written: 0,
+        }
+    }
+    fn hash_zeros(&mut self, end: u64) {
+        let zeros = [0; 64 * 1024];
+        while self.size < end {
+            let size = (end - self.size).min(zeros.len() as u64);
+            self.hasher.update(&zeros[..size as usize]);
+            self.size += size;
+        }
+    }
+    /// Returns the size and checksum of the whole file.
+    pub(crate) fn finish(mut self) -> Result<(u64, String), Error> {
+        if let Some(map) = self.sparse.take() {
+            let written: u64 = map.regions[..self.index.min(map.regions.len())]
+                .iter()
+                .map(|(_, length)| length)
+                .sum::<u64>()
+                + self.written;
+            if written != map.data_size() {
+                return Err(Error::InvalidFrame {
+                    message: "sparse data is truncated".to_string(),
+                });
+            }
+            self.hash_zeros(map.size);
+        }
+        Ok((self.size, self.hasher.finalize().to_hex().to_string()))
+    }
+}
+
+impl AsyncWrite for HashWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.sparse.is_none() {
+            self.hasher.update(buf);
+            self.size += buf.len() as u64;
+            return Poll::Ready(Ok(buf.len()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            let region = self
+                .sparse
+                .as_ref()
+                .and_then(|map| map.regions.get(self.index).copied());
+            let Some((offset, length)) = region else {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "sparse data exceeds the map",
+                )));
+            };
+            if self.written == length {
+                self.index += 1;
+                self.written = 0;
+                continue;
+            }
+            if self.written == 0 {
+                self.hash_zeros(offset);
+            }
+            let size = (length - self.written).min(buf.len() as u64) as usize;
+            self.hasher.update(&buf[..size]);
+            self.size += size as u64;
+            self.written += size as u64;
+            return Poll::Ready(Ok(size));
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
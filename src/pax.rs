@@ -0,0 +1,127 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// PAX extended header records the metadata which can't be stored
+// in the tar header, e.g. sub-second timestamps.
+// https://pubs.opengroup.org/onlinepubs/9699919799/utilities/pax.html
+
+use filetime::FileTime;
+use std::collections::BTreeMap;
+use tokio::fs::File;
+use tokio::io::AsyncRead;
+use tokio_tar::{Builder, Entry, EntryType, Header};
+use tracing::warn;
+
+use super::error::Error;
+
+pub(crate) const PAX_MTIME: &str = "mtime";
+pub(crate) const PAX_ATIME: &str = "atime";
+
+/// Records of PAX extended header, they are sorted by key
+/// so that the archive is reproducible.
+pub(crate) type PaxRecords = BTreeMap<String, Vec<u8>>;
+
+/// Encode the record as `<length> <key>=<value>\n`,
+/// the length includes the digits of itself.
+fn encode_record(key: &str, value: &[u8]) -> Vec<u8> {
+    // space, equals sign and newline
+    let size = key.len() + value.len() + 3;
+    let mut length = size + size.to_string().len();
+    if length.to_string().len() + size != length {
+        length += 1;
+    }
+    let mut record = format!("{length} {key}=").into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+pub(crate) fn format_time(time: FileTime) -> Vec<u8> {
+    let (seconds, nanoseconds) = (time.seconds(), time.nanoseconds());
+    if nanoseconds == 0 {
+        return seconds.to_string().into_bytes();
+    }
+    // the fraction of negative time is also negative, e.g. -0.5
+    if seconds < 0 {
+        return format!("-{}.{:09}", -(seconds + 1), 1_000_000_000 - nanoseconds).into_bytes();
+    }
+    format!("{seconds}.{nanoseconds:09}").into_bytes()
+}
+
+pub(crate) fn parse_time(value: &[u8]) -> Option<FileTime> {
+    let value = std::str::from_utf8(value).ok()?;
+    let (seconds, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let seconds: i64 = seconds.parse().ok()?;
+    let digits = &fraction[..fraction.len().min(9)];
+    let nanoseconds = if digits.is_empty() {
+        0
+    } else {
+        digits.parse::<u32>().ok()? * 10u32.pow(9 - digits.len() as u32)
+    };
+    if value.starts_with('-') && nanoseconds > 0 {
+        return Some(FileTime::from_unix_time(
+            seconds - 1,
+            1_000_000_000 - nanoseconds,
+        ));
+    }
+    Some(FileTime::from_unix_time(seconds, nanoseconds))
+}
+
+/// Append the PAX extended header for the next entry, nothing is appended
+/// if there is no record.
+pub(crate) async fn append_pax(a: &mut Builder<File>, records: &PaxRecords) -> Result<(), Error> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    let data: Vec<u8> = records
+        .iter()
+        .flat_map(|(key, value)| encode_record(key, value))
+        .collect();
+    let mut header = Header::new_ustar();
+    header.set_path("././@PaxHeader")?;
+    header.set_entry_type(EntryType::XHeader);
+    header.set_mode(0o644);
+    header.set_size(data.len() as u64);
+    header.set_cksum();
+    a.append(&header, &data[..]).await?;
+    Ok(())
+}
+
+/// Read the PAX extended records of the entry. The records are split
+/// by newline in tokio-tar, so the malformed records are skipped.
+pub(crate) async fn read_pax<R>(f: &mut Entry<R>) -> Result<PaxRecords, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut records = PaxRecords::new();
+    let mut malformed = 0;
+    if let Some(extensions) = f.pax_extensions().await? {
+        for extension in extensions {
+            let Ok(extension) = extension else {
+                malformed += 1;
+                continue;
+            };
+            if let Ok(key) = extension.key() {
+                records.insert(key.to_string(), extension.value_bytes().to_vec());
+            }
+        }
+    }
+    if malformed > 0 {
+        warn!(
+            file = f.path_bytes().escape_ascii().to_string(),
+            malformed, "skip malformed pax records"
+        );
+    }
+    Ok(records)
+}
@@ -0,0 +1,228 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The detached signature is stored beside the archive as `<archive>.sig`.
+// It signs the blake3 digests of the raw tar entries (header and stored data,
+// the manifest and PAX records included), so the archive is verified
+// without decoding or decrypting and any modified entry is located.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+use tokio_stream::StreamExt;
+use tracing::info;
+
+use super::archiver::check_appending;
+use super::crypto::read_key_file;
+use super::error::Error;
+use super::manifest::open_archive;
+
+pub const SIGNATURE_VERSION: u32 = 1;
+const SIGNATURE_ALGORITHM: &str = "ed25519";
+/// Domain of the signed message, so the signature can't be reused for other data.
+const SIGNATURE_DOMAIN: &[u8] = b"archiver signature v1\n";
+
+/// Digest of the raw tar entry.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SignedEntry {
+    pub path: String,
+    /// Blake3 checksum of the header and stored data of entry
+    pub digest: String,
+}
+
+/// Detached signature of archive.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ArchiveSignature {
+    pub version: u32,
+    pub algorithm: String,
+    /// Public key of the signer as hex
+    pub public_key: String,
+    pub entries: Vec<SignedEntry>,
+    /// Signature of the entries as hex
+    pub signature: String,
+}
+
+fn invalid_signature(message: &str) -> Error {
+    Error::InvalidSignature {
+        message: message.to_string(),
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != N * 2 {
+        return None;
+    }
+    let mut data = [0; N];
+    for (index, b) in data.iter_mut().enumerate() {
+        *b = u8::from_str_radix(value.get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+    Some(data)
+}
+
+/// Fingerprint of the public key, it is the first 16 bytes
+/// of its blake3 checksum as hex joined by colons.
+pub fn fingerprint(public_key: &[u8; 32]) -> String {
+    blake3::hash(public_key).as_bytes()[..16]
+        .chunks(2)
+        .map(to_hex)
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Path of the detached signature, it is `<archive>.sig` by default.
+fn signature_path(target: &str, signature: Option<&str>) -> String {
+    match signature {
+        Some(signature) if !signature.is_empty() => signature.to_string(),
+        _ => format!("{target}.sig"),
+    }
+}
+
+fn signed_message(entries: &[SignedEntry]) -> Result<Vec<u8>, Error> {
+    let mut message = SIGNATURE_DOMAIN.to_vec();
+    message.extend(serde_json::to_vec(entries).map_err(|err| Error::Json { source: err })?);
+    Ok(message)
+}
+
+/// Digests of the raw entries of archive in order.
+async fn entry_digests(target: &str) -> Result<Vec<SignedEntry>, Error> {
+    check_appending(target)?;
+    let mut r = open_archive(target)?;
+    let mut entries = r.entries_raw()?;
+    let mut items = vec![];
+    let mut buf = vec![0; 64 * 1024];
+    while let Some(file) = entries.next().await {
+        let mut f = file?;
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(f.header().as_bytes());
+        loop {
+            let size = f.read(&mut buf).await?;
+            if size == 0 {
+                break;
+            }
+            hasher.update(&buf[..size]);
+        }
+        items.push(SignedEntry {
+            path: String::from_utf8_lossy(&f.path_bytes()).to_string(),
+            digest: hasher.finalize().to_hex().to_string(),
+        });
+    }
+    Ok(items)
+}
+
+/// Sign the archive with the Ed25519 key file of 32 bytes (or 64 hex characters),
+/// the signature is written to `<archive>.sig` if the path is not set.
+/// Returns the fingerprint of the public key.
+pub async fn sign(target: &str, key_file: &Path, signature: Option<&str>) -> Result<String, Error> {
+    if target.is_empty() {
+        return Err(Error::InvalidArg {
+            path: target.to_string(),
+        });
+    }
+    let key = SigningKey::from_bytes(&read_key_file(key_file)?);
+    let public_key = key.verifying_key().to_bytes();
+    let entries = entry_digests(target).await?;
+    let sig = key.sign(&signed_message(&entries)?);
+    let data = ArchiveSignature {
+        version: SIGNATURE_VERSION,
+        algorithm: SIGNATURE_ALGORITHM.to_string(),
+        public_key: to_hex(&public_key),
+        entries,
+        signature: to_hex(&sig.to_bytes()),
+    };
+    let path = signature_path(target, signature);
+    let json = serde_json::to_vec_pretty(&data).map_err(|err| Error::Json { source: err })?;
+    tokio::fs::write(&path, json).await?;
+    let fingerprint = fingerprint(&public_key);
+    info!(
+        file = path,
+        public_key = data.public_key,
+        fingerprint,
+        "sign success"
+    );
+    Ok(fingerprint)
+}
+
+/// Verify the detached signature of archive without extracting it,
+/// returns the fingerprint of the signer's public key. If the public key
+/// file is set, the archive must be signed by it. Otherwise the public key
+/// stored in the signature file is used, which only proves the archive is
+/// not modified after signing, the caller should check the fingerprint.
+pub async fn verify_signature(
+    target: &str,
+    signature: Option<&str>,
+    public_key_file: Option<&Path>,
+) -> Result<String, Error> {
+    if target.is_empty() {
+        return Err(Error::InvalidArg {
+            path: target.to_string(),
+        });
+    }
+    let data = tokio::fs::read(signature_path(target, signature)).await?;
+    let data: ArchiveSignature =
+        serde_json::from_slice(&data).map_err(|err| Error::Json { source: err })?;
+    if data.version != SIGNATURE_VERSION || data.algorithm != SIGNATURE_ALGORITHM {
+        return Err(invalid_signature(&format!(
+            "{} version {} is not supported",
+            data.algorithm, data.version
+        )));
+    }
+    let public_key =
+        from_hex::<32>(&data.public_key).ok_or_else(|| invalid_signature("invalid public key"))?;
+    let fingerprint = fingerprint(&public_key);
+    if let Some(file) = public_key_file
+        && read_key_file(file)? != public_key
+    {
+        return Err(Error::UntrustedSigner { fingerprint });
+    }
+    let key = VerifyingKey::from_bytes(&public_key)
+        .map_err(|_| invalid_signature("invalid public key"))?;
+    let sig = from_hex::<64>(&data.signature).ok_or_else(|| invalid_signature("invalid hex"))?;
+    key.verify_strict(
+        &signed_message(&data.entries)?,
+        &Signature::from_bytes(&sig),
+    )
+    .map_err(|_| invalid_signature("signature mismatch"))?;
+
+    // the signed entries are compared with the archive
+    let entries = entry_digests(target).await?;
+    for (index, entry) in entries.iter().enumerate() {
+        match data.entries.get(index) {
+            Some(signed) if signed == entry => {}
+            Some(signed) => {
+                return Err(invalid_signature(&format!(
+                    "entry {} is modified",
+                    signed.path
+                )));
+            }
+            None => {
+                return Err(invalid_signature(&format!(
+                    "entry {} is not signed",
+                    entry.path
+                )));
+            }
+        }
+    }
+    if let Some(signed) = data.entries.get(entries.len()) {
+        return Err(invalid_signature(&format!(
+            "entry {} is missing",
+            signed.path
+        )));
+    }
+    Ok(fingerprint)
+}
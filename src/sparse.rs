@@ -0,0 +1,297 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Only the data regions of sparse file are compressed into the entry,
+// the regions are recorded as PAX records, so the holes can be
+// recreated when it is extracted.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::error::Error;
+use super::pax::PaxRecords;
+
+pub(crate) const PAX_SPARSE_SIZE: &str = "ARCHIVER.sparse.size";
+pub(crate) const PAX_SPARSE_MAP: &str = "ARCHIVER.sparse.map";
+
+/// Data regions of the sparse file as (offset, length).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct SparseMap {
+    pub(crate) size: u64,
+    pub(crate) regions: Vec<(u64, u64)>,
+}
+
+impl SparseMap {
+    pub(crate) fn to_records(&self, records: &mut PaxRecords) {
+        let map: Vec<String> = self
+            .regions
+            .iter()
+            .map(|(offset, length)| format!("{offset},{length}"))
+            .collect();
+        records.insert(
+            PAX_SPARSE_SIZE.to_string(),
+            self.size.to_string().into_bytes(),
+        );
+        records.insert(PAX_SPARSE_MAP.to_string(), map.join(",").into_bytes());
+    }
+    pub(crate) fn from_records(records: &PaxRecords) -> Result<Option<Self>, Error> {
+        let (Some(size), Some(map)) = (records.get(PAX_SPARSE_SIZE), records.get(PAX_SPARSE_MAP))
+        else {
+            return Ok(None);
+        };
+        let invalid = || Error::InvalidFrame {
+            message: "invalid sparse map".to_string(),
+        };
+        let size: u64 = std::str::from_utf8(size)
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(invalid)?;
+        let values = std::str::from_utf8(map)
+            .ok()
+            .filter(|map| !map.is_empty())
+            .map(|map| {
+                map.split(',')
+                    .map(|value| value.parse::<u64>())
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .unwrap_or(Ok(vec![]))
+            .map_err(|_| invalid())?;
+        if values.len() % 2 != 0 {
+            return Err(invalid());
+        }
+        let regions: Vec<(u64, u64)> = values.chunks(2).map(|v| (v[0], v[1])).collect();
+        let mut end = 0;
+        for (offset, length) in regions.iter() {
+            if *offset < end || offset.checked_add(*length).is_none_or(|e| e > size) {
+                return Err(invalid());
+            }
+            end = offset + length;
+        }
+        Ok(Some(Self { size, regions }))
+    }
+    /// Size of the data regions.
+    pub(crate) fn data_size(&self) -> u64 {
+        self.regions.iter().map(|(_, length)| length).sum()
+    }
+}
+
+/// Detect the data regions of file by SEEK_DATA and SEEK_HOLE,
+/// returns none if the file has no hole.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub(crate) fn sparse_map(file: &std::fs::File) -> Result<Option<SparseMap>, Error> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    let meta = file.metadata()?;
+    let size = meta.len();
+    // the allocated size of file with holes is less than its size
+    if meta.blocks() * 512 >= size {
+        return Ok(None);
+    }
+    let fd = file.as_raw_fd();
+    let mut regions = vec![];
+    let mut offset = 0;
+    while offset < size {
+        // SAFETY: lseek only changes the offset of the valid file descriptor
+        let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            let err = std::io::Error::last_os_error();
+            // ENXIO means no data after the offset
+            if err.raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return Err(err.into());
+        }
+        // SAFETY: same as above
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let (start, end) = (start as u64, (end as u64).min(size));
+        regions.push((start, end - start));
+        offset = end;
+    }
+    if let [(0, length)] = regions[..]
+        && length == size
+    {
+        return Ok(None);
+    }
+    Ok(Some(SparseMap { size, regions }))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+pub(crate) fn sparse_map(_file: &std::fs::File) -> Result<Option<SparseMap>, Error> {
+    Ok(None)
+}
+
+/// Reader of the data regions of sparse file, it calculates the
+/// size and blake3 checksum of the whole file with holes.
+/// The file is read with blocking io as the regions are seeked.
+pub(crate) struct SparseReader {
+    file: std::fs::File,
+    map: SparseMap,
+    index: usize,
+    // read size of current region
+    read: u64,
+    hasher: blake3::Hasher,
+    size: u64,
+}
+
+impl SparseReader {
+    pub(crate) fn new(file: std::fs::File, map: SparseMap) -> Self {
+        Self {
+            file,
+            map,
+            index: 0,
+            read: 0,
+            hasher: blake3::Hasher::new(),
+            size: 0,
+        }
+    }
+    /// The hole is hashed as zeros.
+    fn hash_hole(&mut self, end: u64) {
+        let zeros = [0; 64 * 1024];
+        while self.size < end {
+            let size = (end - self.size).min(zeros.len() as u64);
+            self.hasher.update(&zeros[..size as usize]);
+            self.size += size;
+        }
+    }
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+    pub(crate) fn checksum(&self) -> String {
+        self.hasher.finalize().to_hex().to_string()
+    }
+}
+
+impl AsyncRead for SparseReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            let Some(&(offset, length)) = self.map.regions.get(self.index) else {
+                let size = self.map.size;
+                self.hash_hole(size);
+                return Poll::Ready(Ok(()));
+            };
+            if self.read == length {
+                self.index += 1;
+                self.read = 0;
+                continue;
+            }
+            if self.read == 0 {
+                self.hash_hole(offset);
+                self.file.seek(SeekFrom::Start(offset))?;
+            }
+            let remaining = (length - self.read).min(buf.remaining() as u64) as usize;
+            let size = self
+                .file
+                .read(&mut buf.initialize_unfilled()[..remaining])?;
+            if size == 0 {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "sparse file is truncated",
+                )));
+            }
+            let data = &buf.initialize_unfilled()[..size];
+            self.hasher.update(data);
+            buf.advance(size);
+            self.read += size as u64;
+            self.size += size as u64;
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+/// Writer of the sparse file, the data is written to the regions and
+/// the holes are left unwritten. The file is written with blocking io.
+pub(crate) struct SparseWriter {
+    file: std::fs::File,
+    map: SparseMap,
+    index: usize,
+    // written size of current region
+    written: u64,
+}
+
+impl SparseWriter {
+    pub(crate) fn new(file: std::fs::File, map: SparseMap) -> Self {
+        Self {
+            file,
+            map,
+            index: 0,
+            written: 0,
+        }
+    }
+    /// Set the size of file, returns the size.
+    pub(crate) fn finish(self) -> Result<u64, Error> {
+        let written: u64 = self.map.regions[..self.index.min(self.map.regions.len())]
+            .iter()
+            .map(|(_, length)| length)
+            .sum::<u64>()
+            + self.written;
+        if written != self.map.data_size() {
+            return Err(Error::InvalidFrame {
+                message: "sparse data is truncated".to_string(),
+            });
+        }
+        self.file.set_len(self.map.size)?;
+        Ok(self.map.size)
+    }
+}
+
+impl AsyncWrite for SparseWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            let Some(&(offset, length)) = self.map.regions.get(self.index) else {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "sparse data exceeds the map",
+                )));
+            };
+            if self.written == length {
+                self.index += 1;
+                self.written = 0;
+                continue;
+            }
+            if self.written == 0 {
+                self.file.seek(SeekFrom::Start(offset))?;
+            }
+            let size = (length - self.written).min(buf.len() as u64) as usize;
+            let size = self.file.write(&buf[..size])?;
+            self.written += size as u64;
+            return Poll::Ready(Ok(size));
+        }
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(self.file.flush())
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Extended attributes and POSIX ACLs are recorded as PAX records
+// with the keys used by GNU tar and star, so the archive can be
+// extracted by them too. The PAX records are split by newline when
+// read, so the values with newline are recorded as base64 with the key
+// used by libarchive.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::path::Path;
+use tracing::warn;
+
+use super::pax::PaxRecords;
+
+pub(crate) const PAX_XATTR: &str = "SCHILY.xattr.";
+pub(crate) const PAX_LIBARCHIVE_XATTR: &str = "LIBARCHIVE.xattr.";
+pub(crate) const PAX_ACL_ACCESS: &str = "SCHILY.acl.access";
+pub(crate) const PAX_ACL_DEFAULT: &str = "SCHILY.acl.default";
+
+/// ACLs are stored as these xattrs on Linux
+const XATTR_ACL_ACCESS: &str = "system.posix_acl_access";
+const XATTR_ACL_DEFAULT: &str = "system.posix_acl_default";
+
+const ACL_VERSION: u32 = 2;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+fn warn_unsupported(path: &Path, name: &str, message: &str) {
+    warn!(
+        file = path.to_string_lossy().to_string(),
+        name, message, "extended attribute is not supported"
+    );
+}
+
+/// PAX record of the xattr, the name of libarchive key is percent-encoded.
+fn xattr_record(name: &str, value: Vec<u8>) -> (String, Vec<u8>) {
+    if !value.contains(&b'\n') && !name.contains('=') {
+        return (format!("{PAX_XATTR}{name}"), value);
+    }
+    let name: String = name
+        .bytes()
+        .map(|b| match b {
+            b'%' | b'=' => format!("%{b:02X}"),
+            0x21..=0x7e => (b as char).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect();
+    (
+        format!("{PAX_LIBARCHIVE_XATTR}{name}"),
+        STANDARD.encode(value).into_bytes(),
+    )
+}
+
+/// Name and value of the xattr from the PAX record.
+fn parse_xattr_record(key: &str, value: &[u8]) -> Option<(String, Vec<u8>)> {
+    if let Some(name) = key.strip_prefix(PAX_XATTR) {
+        return Some((name.to_string(), value.to_vec()));
+    }
+    let name = key.strip_prefix(PAX_LIBARCHIVE_XATTR)?.as_bytes();
+    let mut decoded = vec![];
+    let mut index = 0;
+    while index < name.len() {
+        if name[index] == b'%' {
+            let hex = std::str::from_utf8(name.get(index + 1..index + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(name[index]);
+            index += 1;
+        }
+    }
+    let value = STANDARD.decode(value).ok()?;
+    Some((String::from_utf8(decoded).ok()?, value))
+}
+
+/// Whether the PAX record is an extended attribute or ACL.
+pub(crate) fn is_xattr_record(key: &str, xattrs: bool, acls: bool) -> bool {
+    (xattrs && (key.starts_with(PAX_XATTR) || key.starts_with(PAX_LIBARCHIVE_XATTR)))
+        || (acls && (key == PAX_ACL_ACCESS || key == PAX_ACL_DEFAULT))
+}
+
+/// Convert the ACL xattr value to the text form,
+/// e.g. `user::rw-,user:1000:r--,group::r--,mask::r--,other::---`.
+fn acl_to_text(value: &[u8]) -> Option<String> {
+    let version = u32::from_le_bytes(value.get(..4)?.try_into().ok()?);
+    if version != ACL_VERSION || !(value.len() - 4).is_multiple_of(8) {
+        return None;
+    }
+    let mut entries = vec![];
+    for entry in value[4..].chunks(8) {
+        let tag = u16::from_le_bytes([entry[0], entry[1]]);
+        let perm = u16::from_le_bytes([entry[2], entry[3]]);
+        let id = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+        let (tag, qualifier) = match tag {
+            ACL_USER_OBJ => ("user", String::new()),
+            ACL_USER => ("user", id.to_string()),
+            ACL_GROUP_OBJ => ("group", String::new()),
+            ACL_GROUP => ("group", id.to_string()),
+            ACL_MASK => ("mask", String::new()),
+            ACL_OTHER => ("other", String::new()),
+            _ => return None,
+        };
+        let perm: String = [(4, 'r'), (2, 'w'), (1, 'x')]
+            .iter()
+            .map(|(bit, c)| if perm & bit != 0 { *c } else { '-' })
+            .collect();
+        entries.push(format!("{tag}:{qualifier}:{perm}"));
+    }
+    Some(entries.join(","))
+}
+
+/// Convert the ACL text form to the xattr value, the qualifier
+/// must be numeric id and abbreviated tags are accepted.
+fn acl_from_text(text: &str) -> Option<Vec<u8>> {
+    let mut value = ACL_VERSION.to_le_bytes().to_vec();
+    for entry in text.split([',', '\n']) {
+        // the comment of entry, e.g. `group::rwx #effective:r-x`
+        let entry = entry.split('#').next().unwrap_or_default().trim();
+        if entry.is_empty() {
+            continue;
+        }
+        let mut fields = entry.split(':');
+        let (tag, qualifier, perm) = (fields.next()?, fields.next()?, fields.next()?);
+        let id = if qualifier.is_empty() {
+            ACL_UNDEFINED_ID
+        } else {
+            qualifier.parse().ok()?
+        };
+        let tag = match (tag, id == ACL_UNDEFINED_ID) {
+            ("user" | "u", true) => ACL_USER_OBJ,
+            ("user" | "u", false) => ACL_USER,
+            ("group" | "g", true) => ACL_GROUP_OBJ,
+            ("group" | "g", false) => ACL_GROUP,
+            ("mask" | "m", _) => ACL_MASK,
+            ("other" | "o", _) => ACL_OTHER,
+            _ => return None,
+        };
+        let mut bits = 0u16;
+        for c in perm.chars() {
+            bits |= match c {
+                'r' => 4,
+                'w' => 2,
+                'x' => 1,
+                '-' => 0,
+                _ => return None,
+            };
+        }
+        value.extend_from_slice(&tag.to_le_bytes());
+        value.extend_from_slice(&bits.to_le_bytes());
+        value.extend_from_slice(&id.to_le_bytes());
+    }
+    Some(value)
+}
+
+/// Read the extended attributes and ACLs of the path as PAX records,
+/// the attributes which can't be read are skipped with warnings.
+#[cfg(unix)]
+pub(crate) fn read_xattrs(path: &Path, xattrs: bool, acls: bool) -> PaxRecords {
+    let mut records = PaxRecords::new();
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(err) => {
+            warn_unsupported(path, "", &err.to_string());
+            return records;
+        }
+    };
+    for name in names {
+        let name = name.to_string_lossy().to_string();
+        let key = match name.as_str() {
+            XATTR_ACL_ACCESS if acls => PAX_ACL_ACCESS,
+            XATTR_ACL_DEFAULT if acls => PAX_ACL_DEFAULT,
+            XATTR_ACL_ACCESS | XATTR_ACL_DEFAULT => continue,
+            _ if xattrs => "",
+            _ => continue,
+        };
+        let value = match xattr::get(path, &name) {
+            Ok(Some(value)) => value,
+            Ok(None) => continue,
+            Err(err) => {
+                warn_unsupported(path, &name, &err.to_string());
+                continue;
+            }
+        };
+        if key.is_empty() {
+            let (key, value) = xattr_record(&name, value);
+            records.insert(key, value);
+            continue;
+        }
+        match acl_to_text(&value) {
+            Some(text) => {
+                records.insert(key.to_string(), text.into_bytes());
+            }
+            None => warn_unsupported(path, &name, "invalid acl"),
+        }
+    }
+    records
+}
+
+#[cfg(not(unix))]
+pub(crate) fn read_xattrs(path: &Path, _xattrs: bool, _acls: bool) -> PaxRecords {
+    warn_unsupported(path, "", "platform is not supported");
+    PaxRecords::new()
+}
+
+/// Apply the extended attributes and ACLs of the PAX records to the path,
+/// the attributes which can't be set are skipped with warnings.
+#[cfg(unix)]
+pub(crate) fn apply_xattrs(path: &Path, records: &PaxRecords) {
+    for (key, value) in records {
+        let (name, value) = if let Some((name, value)) = parse_xattr_record(key, value) {
+            (name, value)
+        } else {
+            let name = match key.as_str() {
+                PAX_ACL_ACCESS => XATTR_ACL_ACCESS,
+                PAX_ACL_DEFAULT => XATTR_ACL_DEFAULT,
+                _ => {
+                    warn_unsupported(path, key, "invalid record");
+                    continue;
+                }
+            };
+            let acl = std::str::from_utf8(value).ok().and_then(acl_from_text);
+            let Some(acl) = acl else {
+                warn_unsupported(path, name, "invalid acl");
+                continue;
+            };
+            (name.to_string(), acl)
+        };
+        if let Err(err) = xattr::set(path, &name, &value) {
+            warn_unsupported(path, &name, &err.to_string());
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn apply_xattrs(path: &Path, records: &PaxRecords) {
+    if !records.is_empty() {
+        warn_unsupported(path, "", "platform is not supported");
+    }
+}
@@ -0,0 +1,370 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use archiver::{
+    ArchiveParams, BoxFuture, Cipher, Codec, Compression, Encryption, Error, INDEX_PATH, KeySource,
+    MANIFEST_PATH, Reader, Writer, archive, list, read_index, read_manifest, register_codec,
+    repair, verify,
+};
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_stream::StreamExt;
+
+mod common;
+use common::{create_source, params, unarchive_all, unarchive_file};
+
+// Store the data as it is, fails if the data starts with "encode error",
+// and never finishes if it starts with "hang".
+struct Flaky;
+
+impl Codec for Flaky {
+    fn name(&self) -> &str {
+        "flaky"
+    }
+    fn encode<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+        _level: i32,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move {
+            let mut data = vec![];
+            reader.read_to_end(&mut data).await?;
+            if data.starts_with(b"encode error") {
+                return Err(std::io::Error::other("encode error").into());
+            }
+            if data.starts_with(b"hang") {
+                writer.write_all(&data).await?;
+                writer.flush().await?;
+                std::future::pending::<()>().await;
+            }
+            writer.write_all(&data).await?;
+            Ok(data.len() as u64)
+        })
+    }
+    fn decode<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move { Ok(tokio::io::copy(reader, writer).await?) })
+    }
+}
+
+fn append_params(source: &Path, target: &Path) -> ArchiveParams {
+    ArchiveParams {
+        append: true,
+        ..params(source, target)
+    }
+}
+
+fn write_logs(dir: &Path, logs: &[(&str, &str)]) {
+    let _ = fs::remove_dir_all(dir);
+    create_source(dir, logs);
+}
+
+#[tokio::test]
+async fn append_files() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("logs");
+    let output = dir.path().join("output");
+    let target = dir.path().join("logs.zst.tar");
+
+    for (stream, logs) in [
+        (false, [("00.log", "first hour")]),
+        (true, [("01.log", "second hour")]),
+        (false, [("02.log", "third hour")]),
+        // the file of the same path is replaced
+        (true, [("00.log", "first hour again")]),
+    ] {
+        write_logs(&source, &logs);
+        archive(ArchiveParams {
+            stream,
+            ..append_params(&source, &target)
+        })
+        .await
+        .unwrap();
+    }
+
+    let files = [
+        ("00.log", "first hour again"),
+        ("01.log", "second hour"),
+        ("02.log", "third hour"),
+    ];
+    unarchive_all(&target, &output, None).await;
+    for (path, data) in files {
+        assert_eq!(data.as_bytes(), fs::read(output.join(path)).unwrap());
+    }
+    for (path, data) in files {
+        assert_eq!(
+            data.as_bytes(),
+            unarchive_file(&target, &output, path).await
+        );
+    }
+
+    let target_str = target.to_string_lossy().to_string();
+    let manifest = read_manifest(&target_str).await.unwrap().unwrap();
+    assert_eq!(3, manifest.files.len());
+    assert_eq!(
+        16,
+        manifest
+            .file("00.log")
+            .map(|file| file.size)
+            .unwrap_or_default()
+    );
+    let index = read_index(&target_str).await.unwrap().unwrap();
+    assert!(index.manifest.is_some());
+    assert_eq!(4, index.entries.len());
+    assert_eq!(4, list(&target_str).await.unwrap().len());
+    let items = verify(&target_str, None, None).await.unwrap();
+    assert_eq!(4, items.len());
+    assert!(items.iter().all(|item| item.is_valid()));
+
+    // the previous index and appended manifest are overwritten
+    let data = fs::read(&target).unwrap();
+    let mut r = tokio_tar::Archive::new(&data[..]);
+    let mut entries = r.entries().unwrap();
+    let mut paths = vec![];
+    while let Some(file) = entries.next().await {
+        paths.push(file.unwrap().path().unwrap().to_string_lossy().to_string());
+    }
+    assert_eq!(
+        vec![
+            MANIFEST_PATH,
+            "00.log",
+            "01.log",
+            "02.log",
+            "00.log",
+            MANIFEST_PATH,
+            INDEX_PATH
+        ],
+        paths
+    );
+}
+
+#[tokio::test]
+async fn append_mismatch() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("logs");
+    let target = dir.path().join("logs.gz.tar");
+    write_logs(&source, &[("00.log", "first hour")]);
+    archive(append_params(&source, &target)).await.unwrap();
+    let data = fs::read(&target).unwrap();
+
+    let result = archive(ArchiveParams {
+        compression: Some(Compression::Zstd),
+        ..append_params(&source, &target)
+    })
+    .await;
+    assert!(matches!(result, Err(Error::CompressionMismatch { .. })));
+    let result = archive(ArchiveParams {
+        encryption: Some(Encryption {
+            cipher: Cipher::Aes256Gcm,
+            key: KeySource::Passphrase("secret".to_string()),
+            names: false,
+        }),
+        ..append_params(&source, &target)
+    })
+    .await;
+    assert!(matches!(result, Err(Error::InvalidKey { .. })));
+    assert_eq!(data, fs::read(&target).unwrap());
+
+    // the archive is restored if it fails
+    register_codec(Flaky).unwrap();
+    let target = dir.path().join("logs.flaky.tar");
+    archive(append_params(&source, &target)).await.unwrap();
+    let data = fs::read(&target).unwrap();
+    write_logs(
+        &source,
+        &[("01.log", "second hour"), ("02.log", "encode error")],
+    );
+    assert!(archive(append_params(&source, &target)).await.is_err());
+    assert_eq!(data, fs::read(&target).unwrap());
+}
+
+#[tokio::test]
+async fn append_interrupted() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("logs");
+    let output = dir.path().join("output");
+    let target = dir.path().join("logs.flaky.tar");
+    let target_str = target.to_string_lossy().to_string();
+    let marker = dir.path().join("logs.flaky.tar.appending");
+    register_codec(Flaky).unwrap();
+
+    write_logs(&source, &[("00.log", "first hour")]);
+    archive(append_params(&source, &target)).await.unwrap();
+    let data = fs::read(&target).unwrap();
+
+    // the append is dropped halfway as if the process crashed
+    let crash = async || {
+        write_logs(&source, &[("01.log", "hang up")]);
+        let result = tokio::time::timeout(
+            std::time::Duration::from_millis(500),
+            archive(ArchiveParams {
+                stream: true,
+                ..append_params(&source, &target)
+            }),
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(marker.exists());
+        assert_ne!(data, fs::read(&target).unwrap());
+    };
+    crash().await;
+
+    // the readers fail without modifying the archive
+    let interrupted = fs::read(&target).unwrap();
+    let result = list(&target_str).await;
+    assert!(matches!(result, Err(Error::AppendInProgress { .. })));
+    let result = verify(&target_str, None, None).await;
+    assert!(matches!(result, Err(Error::AppendInProgress { .. })));
+    assert!(marker.exists());
+    assert_eq!(interrupted, fs::read(&target).unwrap());
+
+    // the writers fail while the archive is locked
+    let lock = fs::File::open(&target).unwrap();
+    lock.try_lock().unwrap();
+    let result = archive(append_params(&source, &target)).await;
+    assert!(matches!(result, Err(Error::AppendInProgress { .. })));
+    let result = repair(&target_str).await;
+    assert!(matches!(result, Err(Error::AppendInProgress { .. })));
+    drop(lock);
+
+    assert!(repair(&target_str).await.unwrap());
+    assert!(!marker.exists());
+    assert_eq!(data, fs::read(&target).unwrap());
+    assert!(!repair(&target_str).await.unwrap());
+
+    // the next append rolls back the interrupted one first
+    crash().await;
+    write_logs(&source, &[("01.log", "second hour")]);
+    archive(append_params(&source, &target)).await.unwrap();
+    assert!(!marker.exists());
+    let entries = list(&target_str).await.unwrap();
+    assert_eq!(
+        vec!["00.log", "01.log"],
+        entries
+            .iter()
+            .map(|item| item.path.as_str())
+            .collect::<Vec<_>>()
+    );
+    unarchive_all(&target, &output, None).await;
+    assert_eq!(b"first hour", &fs::read(output.join("00.log")).unwrap()[..]);
+    assert_eq!(
+        b"second hour",
+        &fs::read(output.join("01.log")).unwrap()[..]
+    );
+}
+
+#[tokio::test]
+async fn append_encrypted() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("logs");
+    let output = dir.path().join("output");
+    let target = dir.path().join("logs.br.tar");
+    let key_file = dir.path().join("archive.key");
+    fs::write(&key_file, [3; 32]).unwrap();
+    let key = KeySource::File(key_file);
+    let encryption = Encryption {
+        cipher: Cipher::ChaCha20Poly1305,
+        key: key.clone(),
+        names: true,
+    };
+
+    write_logs(&source, &[("00.log", "first hour")]);
+    archive(ArchiveParams {
+        encryption: Some(encryption.clone()),
+        ..append_params(&source, &target)
+    })
+    .await
+    .unwrap();
+    write_logs(&source, &[("01.log", "second hour")]);
+    let result = archive(append_params(&source, &target)).await;
+    assert!(matches!(result, Err(Error::InvalidKey { .. })));
+    archive(ArchiveParams {
+        encryption: Some(encryption),
+        ..append_params(&source, &target)
+    })
+    .await
+    .unwrap();
+
+    unarchive_all(&target, &output, Some(key.clone())).await;
+    assert_eq!(
+        b"first hour".to_vec(),
+        fs::read(output.join("00.log")).unwrap()
+    );
+    assert_eq!(
+        b"second hour".to_vec(),
+        fs::read(output.join("01.log")).unwrap()
+    );
+    let items = verify(&target.to_string_lossy(), None, Some(&key))
+        .await
+        .unwrap();
+    assert_eq!(2, items.len());
+    assert!(
+        items
+            .iter()
+            .all(|item| item.is_valid() && item.expected.is_some())
+    );
+}
+
+#[tokio::test]
+async fn append_without_index() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("logs");
+    let output = dir.path().join("output");
+    let target = dir.path().join("logs.lz4.tar");
+    write_logs(&source, &[("00.log", "first hour")]);
+    archive(append_params(&source, &target)).await.unwrap();
+
+    // remove the index entry, the archive ends with two zero blocks
+    let index_offset = fs::metadata(&target).unwrap().len() - 1024 - 1024;
+    let mut file = fs::OpenOptions::new().write(true).open(&target).unwrap();
+    file.set_len(index_offset).unwrap();
+    file.seek(SeekFrom::End(0)).unwrap();
+    file.write_all(&[0; 1024]).unwrap();
+    drop(file);
+    let target_str = target.to_string_lossy().to_string();
+    assert_eq!(None, read_index(&target_str).await.unwrap());
+
+    write_logs(&source, &[("01.log", "second hour")]);
+    archive(append_params(&source, &target)).await.unwrap();
+    let index = read_index(&target_str).await.unwrap().unwrap();
+    assert_eq!(
+        vec!["00.log", "01.log"],
+        index
+            .entries
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        b"first hour".to_vec(),
+        unarchive_file(&target, &output, "00.log").await
+    );
+    assert_eq!(
+        b"second hour".to_vec(),
+        unarchive_file(&target, &output, "01.log").await
+    );
+    let items = verify(&target_str, None, None).await.unwrap();
+    assert!(
+        items
+            .iter()
+            .all(|item| item.is_valid() && item.expected.is_some())
+    );
+}
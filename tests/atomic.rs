@@ -0,0 +1,139 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use archiver::{
+    ArchiveParams, BoxFuture, Codec, Error, Reader, UnarchiveParams, Writer, archive,
+    register_codec, unarchive,
+};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// Store the data as it is, fails if the data starts with the error message.
+struct Flaky;
+
+impl Codec for Flaky {
+    fn name(&self) -> &str {
+        "flaky"
+    }
+    fn encode<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+        _level: i32,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move {
+            let mut data = vec![];
+            reader.read_to_end(&mut data).await?;
+            if data.starts_with(b"encode error") {
+                return Err(std::io::Error::other("encode error").into());
+            }
+            writer.write_all(&data).await?;
+            Ok(data.len() as u64)
+        })
+    }
+    fn decode<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move {
+            let mut data = vec![];
+            reader.read_to_end(&mut data).await?;
+            // the data is written before failing
+            writer.write_all(&data).await?;
+            if data.starts_with(b"decode error") {
+                return Err(std::io::Error::other("decode error").into());
+            }
+            Ok(data.len() as u64)
+        })
+    }
+}
+
+// Names of the temp files in the directory and its sub directories.
+fn temp_files(dir: &Path) -> Vec<String> {
+    let mut files = vec![];
+    let Ok(entries) = fs::read_dir(dir) else {
+        return files;
+    };
+    for entry in entries {
+        let entry = entry.unwrap();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type().unwrap().is_dir() {
+            files.extend(temp_files(&entry.path()));
+        } else if name.starts_with(".archiver-") && name.ends_with(".tmp") {
+            files.push(name);
+        }
+    }
+    files
+}
+
+#[tokio::test]
+async fn atomic_output() {
+    register_codec(Flaky).unwrap();
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    fs::create_dir_all(source.join("sub")).unwrap();
+    fs::write(source.join("hello.txt"), b"hello world").unwrap();
+    fs::write(source.join("sub/broken.txt"), b"encode error").unwrap();
+    let target = dir.path().join("source.flaky.tar");
+    let params = ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target.to_string_lossy().to_string(),
+        pattern: "/**/*".to_string(),
+        ..Default::default()
+    };
+
+    // the failed archive is not written to the target
+    assert!(archive(params.clone()).await.is_err());
+    assert!(!target.exists());
+    assert!(temp_files(dir.path()).is_empty());
+
+    // the previous archive is kept if it fails
+    fs::write(&target, b"previous archive").unwrap();
+    assert!(archive(params.clone()).await.is_err());
+    assert_eq!(b"previous archive".to_vec(), fs::read(&target).unwrap());
+    assert!(temp_files(dir.path()).is_empty());
+
+    // the partial file is not left in the output
+    fs::write(source.join("sub/broken.txt"), b"decode error").unwrap();
+    archive(params.clone()).await.unwrap();
+    assert!(temp_files(dir.path()).is_empty());
+    let result = unarchive(UnarchiveParams {
+        source: target.to_string_lossy().to_string(),
+        target: output.to_string_lossy().to_string(),
+        ..Default::default()
+    })
+    .await;
+    assert!(result.is_err());
+    assert!(!output.join("sub/broken.txt").exists());
+    assert!(temp_files(&output).is_empty());
+
+    fs::remove_file(source.join("sub/broken.txt")).unwrap();
+    archive(params).await.unwrap();
+    unarchive(UnarchiveParams {
+        source: target.to_string_lossy().to_string(),
+        target: output.to_string_lossy().to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        b"hello world".to_vec(),
+        fs::read(output.join("hello.txt")).unwrap()
+    );
+    assert!(temp_files(dir.path()).is_empty());
+}
@@ -0,0 +1,180 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use archiver::{
+    ArchiveParams, BoxFuture, Codec, Compression, Error, Reader, UnarchiveParams, Writer, archive,
+    register_codec, unarchive,
+};
+use std::fs;
+use tempfile::TempDir;
+use tokio::io::copy;
+
+// Store the data as it is.
+struct Store;
+
+impl Codec for Store {
+    fn name(&self) -> &str {
+        "store"
+    }
+    fn encode<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+        _level: i32,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move { Ok(copy(reader, writer).await?) })
+    }
+    fn decode<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        writer: &'a mut Writer<'_>,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move { Ok(copy(reader, writer).await?) })
+    }
+}
+
+#[test]
+fn compression_from_str() {
+    for (value, compression) in [
+        ("gz", Compression::Gzip),
+        ("gzip", Compression::Gzip),
+        ("zst", Compression::Zstd),
+        ("br", Compression::Brotli),
+        ("lz4", Compression::Lz4),
+        ("sz", Compression::Snappy),
+        ("zip", Compression::Deflate),
+        ("xz", Compression::Xz),
+    ] {
+        assert_eq!(compression, value.parse::<Compression>().unwrap());
+    }
+    assert_eq!("zst", Compression::Zstd.to_string());
+    assert!("rar".parse::<Compression>().is_err());
+}
+
+#[test]
+fn compression_from_path() {
+    for (path, compression) in [
+        ("/tmp/fonts.gz.tar", Compression::Gzip),
+        ("backup.2025-01-01.zst.tar", Compression::Zstd),
+        ("/tmp/my.project.xz.tar", Compression::Xz),
+    ] {
+        assert_eq!(compression, Compression::from_path(path).unwrap());
+    }
+    for path in ["fonts.tar", ".gz.tar", "fonts.gz", "/tmp/"] {
+        assert!(Compression::from_path(path).is_err(), "{path}");
+    }
+}
+
+#[tokio::test]
+async fn explicit_compression() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    fs::create_dir_all(&source).unwrap();
+    fs::write(source.join("hello.txt"), b"hello world").unwrap();
+    // the extension is not a compression
+    let target = dir.path().join("backup.2025-01-01.tar");
+
+    archive(ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target.to_string_lossy().to_string(),
+        level: 3,
+        pattern: "/**/*".to_string(),
+        compression: Some(Compression::Zstd),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    unarchive(UnarchiveParams {
+        source: target.to_string_lossy().to_string(),
+        target: output.to_string_lossy().to_string(),
+        compression: Some(Compression::Zstd),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(
+        b"hello world".to_vec(),
+        fs::read(output.join("hello.txt")).unwrap()
+    );
+}
+
+#[test]
+fn register_invalid_codec() {
+    struct Named(&'static str);
+    impl Codec for Named {
+        fn name(&self) -> &str {
+            self.0
+        }
+        fn encode<'a>(
+            &'a self,
+            reader: &'a mut Reader<'_>,
+            writer: &'a mut Writer<'_>,
+            level: i32,
+        ) -> BoxFuture<'a, Result<u64, Error>> {
+            Compression::Gzip.encode(reader, writer, level)
+        }
+        fn decode<'a>(
+            &'a self,
+            reader: &'a mut Reader<'_>,
+            writer: &'a mut Writer<'_>,
+        ) -> BoxFuture<'a, Result<u64, Error>> {
+            Compression::Gzip.decode(reader, writer)
+        }
+    }
+    // the built-in names and the names which break the inference from path
+    for name in ["gz", "", "my.codec", "my/codec"] {
+        assert!(register_codec(Named(name)).is_err(), "{name}");
+    }
+}
+
+#[tokio::test]
+async fn custom_codec() {
+    register_codec(Store).unwrap();
+    assert_eq!(
+        Compression::Custom("store".to_string()),
+        "store".parse::<Compression>().unwrap()
+    );
+
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    fs::create_dir_all(source.join("sub")).unwrap();
+    fs::write(source.join("sub/hello.txt"), b"hello world").unwrap();
+    let target = dir.path().join("source.store.tar");
+
+    archive(ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target.to_string_lossy().to_string(),
+        pattern: "/**/*".to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    unarchive(UnarchiveParams {
+        source: target.to_string_lossy().to_string(),
+        target: output.to_string_lossy().to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(
+        b"hello world".to_vec(),
+        fs::read(output.join("sub/hello.txt")).unwrap()
+    );
+}
@@ -0,0 +1,101 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Helpers shared by the integration tests, each test crate uses a part of them.
+#![allow(dead_code)]
+
+use archiver::{ArchiveParams, Error, KeySource, UnarchiveParams, unarchive};
+use filetime::{FileTime, set_file_mtime};
+use std::fs;
+use std::path::Path;
+
+// Params to archive all files of the source directory.
+pub fn params(source: &Path, target: &Path) -> ArchiveParams {
+    ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target.to_string_lossy().to_string(),
+        level: 3,
+        pattern: "/**/*".to_string(),
+        ..Default::default()
+    }
+}
+
+// Write the files under the directory, their parent directories are created.
+pub fn create_source<D: AsRef<[u8]>>(dir: &Path, files: &[(&str, D)]) {
+    for (path, data) in files {
+        let file = dir.join(path);
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(file, data).unwrap();
+    }
+}
+
+// Write the file under the directory and set its modification time.
+pub fn write_file(dir: &Path, path: &str, data: &str, mtime: i64) {
+    let file = dir.join(path);
+    fs::create_dir_all(file.parent().unwrap()).unwrap();
+    fs::write(&file, data).unwrap();
+    set_file_mtime(&file, FileTime::from_unix_time(mtime, 0)).unwrap();
+}
+
+// Unarchive all files to the output directory with the params.
+pub async fn unarchive_with(
+    target: &Path,
+    output: &Path,
+    params: UnarchiveParams,
+) -> Result<(), Error> {
+    let _ = fs::remove_dir_all(output);
+    unarchive(UnarchiveParams {
+        source: target.to_string_lossy().to_string(),
+        target: output.to_string_lossy().to_string(),
+        ..params
+    })
+    .await
+}
+
+// Unarchive all files to the output directory with the key.
+pub async fn unarchive_key(
+    target: &Path,
+    output: &Path,
+    key: Option<KeySource>,
+) -> Result<(), Error> {
+    unarchive_with(
+        target,
+        output,
+        UnarchiveParams {
+            key,
+            ..Default::default()
+        },
+    )
+    .await
+}
+
+// Unarchive all files with the key, it panics if unarchive fails.
+pub async fn unarchive_all(target: &Path, output: &Path, key: Option<KeySource>) {
+    unarchive_key(target, output, key).await.unwrap();
+}
+
+// Unarchive the file to the output directory and read it.
+pub async fn unarchive_file(target: &Path, output: &Path, file: &str) -> Vec<u8> {
+    unarchive_with(
+        target,
+        output,
+        UnarchiveParams {
+            file: file.to_string(),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    fs::read(output.join(file)).unwrap()
+}
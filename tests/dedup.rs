@@ -0,0 +1,338 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use archiver::{
+    ArchiveParams, CHUNK_DIR, Cipher, Codec, Compression, Encryption, Error, INDEX_PATH,
+    IndexedReader, KeySource, Limit, MANIFEST_PATH, UnarchiveParams, archive, delete,
+    list_with_key, read_manifest, update, verify,
+};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio_tar::{Builder, EntryType, Header};
+
+mod common;
+use common::{create_source, params, unarchive_all, unarchive_key, unarchive_with};
+
+fn dedup_params(source: &Path, target: &Path) -> ArchiveParams {
+    ArchiveParams {
+        dedup: true,
+        ..params(source, target)
+    }
+}
+
+// Incompressible data generated by xorshift.
+fn random_data(size: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed;
+    (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+// Files which share most of their content.
+fn write_files(source: &Path) -> Vec<(&'static str, Vec<u8>)> {
+    let data = random_data(1024 * 1024, 7);
+    let mut inserted = data.clone();
+    inserted.splice(300_000..300_000, b"inserted in the middle".iter().copied());
+    let files = vec![
+        ("a.bin", data.clone()),
+        ("copy/a.bin", data),
+        ("b.bin", inserted),
+        ("small.txt", b"small file".to_vec()),
+        ("empty.txt", vec![]),
+    ];
+    create_source(source, &files);
+    files
+}
+
+#[tokio::test]
+async fn dedup_files() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let plain = dir.path().join("plain.zst.tar");
+    let target = dir.path().join("dedup.zst.tar");
+    let files = write_files(&source);
+    archive(params(&source, &plain)).await.unwrap();
+    archive(dedup_params(&source, &target)).await.unwrap();
+
+    // the shared chunks are stored once
+    let plain_size = fs::metadata(&plain).unwrap().len();
+    let size = fs::metadata(&target).unwrap().len();
+    assert!(size < plain_size / 2, "{size} >= {plain_size} / 2");
+
+    let target_str = target.to_string_lossy().to_string();
+    unarchive_with(
+        &target,
+        &output,
+        UnarchiveParams {
+            jobs: 4,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    for (path, data) in files.iter() {
+        assert_eq!(*data, fs::read(output.join(path)).unwrap(), "{path}");
+    }
+    assert!(!output.join(".archiver-chunks").exists());
+
+    let mut entries = list_with_key(&target_str, None).await.unwrap();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    assert_eq!(
+        vec![
+            "a.bin",
+            "b.bin",
+            "copy",
+            "copy/a.bin",
+            "empty.txt",
+            "small.txt"
+        ],
+        entries.iter().map(|e| e.path.as_str()).collect::<Vec<_>>()
+    );
+    let a = &entries[0];
+    assert_eq!(Some(1024 * 1024), a.size);
+    assert!(a.compressed_size.unwrap() > 1024 * 1024);
+
+    let manifest = read_manifest(&target_str).await.unwrap().unwrap();
+    assert_eq!(5, manifest.files.len());
+    let items = verify(&target_str, None, None).await.unwrap();
+    assert_eq!(5, items.len());
+    assert!(
+        items
+            .iter()
+            .all(|item| item.is_valid() && item.expected.is_some())
+    );
+
+    let reader = IndexedReader::open(&target_str, None, None).await.unwrap();
+    let mut data = vec![];
+    let size = reader.read_file("b.bin", &mut data).await.unwrap();
+    assert_eq!(Some(data.len() as u64), size);
+    assert_eq!(files[2].1, data);
+}
+
+#[tokio::test]
+async fn dedup_encrypted() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let target = dir.path().join("dedup.gz.tar");
+    let key = KeySource::Passphrase("secret".to_string());
+    let files = write_files(&source);
+    archive(ArchiveParams {
+        encryption: Some(Encryption {
+            cipher: Cipher::Aes256Gcm,
+            key: key.clone(),
+            names: true,
+        }),
+        ..dedup_params(&source, &target)
+    })
+    .await
+    .unwrap();
+
+    let target_str = target.to_string_lossy().to_string();
+    let result = unarchive_key(&target, &output, None).await;
+    assert!(result.is_err());
+    unarchive_all(&target, &output, Some(key.clone())).await;
+    for (path, data) in files.iter() {
+        assert_eq!(*data, fs::read(output.join(path)).unwrap(), "{path}");
+    }
+    let items = verify(&target_str, None, Some(&key)).await.unwrap();
+    assert!(items.iter().all(|item| item.is_valid()));
+}
+
+#[tokio::test]
+async fn dedup_append() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let target = dir.path().join("dedup.lz4.tar");
+    fs::create_dir_all(&source).unwrap();
+    let data = random_data(512 * 1024, 11);
+    fs::write(source.join("a.bin"), &data).unwrap();
+    archive(dedup_params(&source, &target)).await.unwrap();
+
+    fs::remove_file(source.join("a.bin")).unwrap();
+    fs::write(source.join("b.bin"), &data[1000..]).unwrap();
+    archive(ArchiveParams {
+        append: true,
+        ..dedup_params(&source, &target)
+    })
+    .await
+    .unwrap();
+    unarchive_all(&target, &output, None).await;
+    assert_eq!(data, fs::read(output.join("a.bin")).unwrap());
+    assert_eq!(data[1000..], fs::read(output.join("b.bin")).unwrap());
+
+    let result = archive(ArchiveParams {
+        stream: true,
+        ..dedup_params(&source, &target)
+    })
+    .await;
+    assert!(matches!(result, Err(Error::InvalidArg { .. })));
+}
+
+#[tokio::test]
+async fn dedup_rewrite() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let target = dir.path().join("dedup.zst.tar");
+    let a = random_data(512 * 1024, 13);
+    let b = random_data(512 * 1024, 17);
+    create_source(&source, &[("a.bin", &a), ("copy/a.bin", &a), ("b.bin", &b)]);
+    archive(dedup_params(&source, &target)).await.unwrap();
+    let target_str = target.to_string_lossy().to_string();
+    let size = fs::metadata(&target).unwrap().len();
+
+    // the chunks of a.bin are referred by its copy
+    delete(&target_str, &["a.bin".to_string()], None, None)
+        .await
+        .unwrap();
+    let kept = fs::metadata(&target).unwrap().len();
+    assert!(kept + 4096 > size, "{kept} + 4 KiB <= {size}");
+
+    // the chunks of b.bin are dropped with it
+    delete(&target_str, &["b.bin".to_string()], None, None)
+        .await
+        .unwrap();
+    let deleted = fs::metadata(&target).unwrap().len();
+    assert!(deleted + 500 * 1024 < kept, "{deleted} + 500 KiB >= {kept}");
+
+    // the chunks of the replaced copy are dropped
+    fs::remove_file(source.join("a.bin")).unwrap();
+    fs::remove_file(source.join("b.bin")).unwrap();
+    fs::write(source.join("copy/a.bin"), &b).unwrap();
+    update(dedup_params(&source, &target)).await.unwrap();
+    let updated = fs::metadata(&target).unwrap().len();
+    assert!(
+        updated < deleted + 100 * 1024,
+        "{updated} >= {deleted} + 100 KiB"
+    );
+
+    unarchive_all(&target, &output, None).await;
+    assert_eq!(b, fs::read(output.join("copy/a.bin")).unwrap());
+    assert!(!output.join("a.bin").exists());
+    let items = verify(&target_str, None, None).await.unwrap();
+    assert!(items.iter().all(|item| item.is_valid()));
+}
+
+#[tokio::test]
+async fn dedup_reserved_paths() {
+    let dir = TempDir::new().unwrap();
+    let target = dir.path().join("dedup.zst.tar");
+    let chunk = format!("{CHUNK_DIR}chunk");
+    // the entries of reserved paths would be skipped by unarchive
+    for (i, path) in [MANIFEST_PATH, INDEX_PATH, chunk.as_str()]
+        .iter()
+        .enumerate()
+    {
+        let source = dir.path().join(format!("source{i}"));
+        create_source(&source, &[("a.txt", "a"), (path, "reserved")]);
+        let result = archive(dedup_params(&source, &target)).await;
+        assert!(matches!(result, Err(Error::UnsafePath { .. })), "{path}");
+        assert!(!target.exists());
+    }
+}
+
+#[tokio::test]
+async fn dedup_invalid_index() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let target = dir.path().join("dedup.zst.tar");
+    fs::create_dir_all(&source).unwrap();
+    // the chunks of zeros are the same and highly compressed
+    fs::write(source.join("zeros.bin"), vec![0; 8 * 1024 * 1024]).unwrap();
+    archive(dedup_params(&source, &target)).await.unwrap();
+    let target_str = target.to_string_lossy().to_string();
+
+    // the ratio is checked with the size of the chunks read
+    let result = unarchive_with(
+        &target,
+        &output,
+        UnarchiveParams {
+            max_ratio: Some(100.0),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert!(matches!(result, Err(Error::LimitExceeded { .. })));
+
+    // the chunk of index is out of the archive
+    let mut data = fs::read(&target).unwrap();
+    let start = data
+        .windows(11)
+        .rposition(|value| value == b"{\"version\":")
+        .unwrap();
+    let json = String::from_utf8(data[start..].to_vec()).unwrap();
+    let offset = json.find("\"offset\":").unwrap() + "\"offset\":".len();
+    let digits = json[offset..].find(|c: char| !c.is_ascii_digit()).unwrap();
+    data[start + offset..start + offset + digits].fill(b'9');
+    fs::write(&target, data).unwrap();
+    let result = unarchive_key(&target, &output, None).await;
+    assert!(matches!(result, Err(Error::InvalidIndex { .. })));
+    let items = verify(&target_str, None, None).await.unwrap();
+    assert!(items.iter().all(|item| !item.is_valid()));
+}
+
+#[tokio::test]
+async fn dedup_oversized_recipe() {
+    let dir = TempDir::new().unwrap();
+    let output = dir.path().join("output");
+    let target = dir.path().join("zeros.gz.tar");
+    // the recipe of zeros is highly compressed, it is rejected past the cap
+    let mut recipe = vec![];
+    Compression::Gzip
+        .encode(
+            &mut tokio::io::repeat(0).take(64 * 1024 * 1024),
+            &mut recipe,
+            6,
+        )
+        .await
+        .unwrap();
+    let mut a = Builder::new(File::create(&target).await.unwrap());
+    let record = b"27 ARCHIVER.chunked.size=1\n";
+    let mut header = Header::new_ustar();
+    header.set_path("././@PaxHeader").unwrap();
+    header.set_entry_type(EntryType::XHeader);
+    header.set_mode(0o644);
+    header.set_size(record.len() as u64);
+    header.set_cksum();
+    a.append(&header, &record[..]).await.unwrap();
+    let mut header = Header::new_gnu();
+    header.set_path("zeros.bin").unwrap();
+    header.set_mode(0o644);
+    header.set_size(recipe.len() as u64);
+    header.set_cksum();
+    a.append(&header, &recipe[..]).await.unwrap();
+    a.finish().await.unwrap();
+
+    let result = unarchive_key(&target, &output, None).await;
+    assert!(matches!(
+        result,
+        Err(Error::LimitExceeded {
+            limit: Limit::EntrySize(_),
+            ..
+        })
+    ));
+    assert!(!output.join("zeros.bin").exists());
+}
@@ -0,0 +1,229 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use archiver::{
+    ArchiveParams, Cipher, Encryption, Error, INDEX_PATH, KeySource, MANIFEST_PATH, archive,
+    list_with_key, read_index, verify,
+};
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use tempfile::TempDir;
+use tokio_stream::StreamExt;
+
+mod common;
+use common::{create_source, unarchive_key};
+
+const SECRET: &[u8] = b"the secret message in plain text";
+
+fn source_files() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("hello.txt", b"hello world".to_vec()),
+        ("empty.txt", vec![]),
+        ("sub/secret.txt", SECRET.repeat(8 * 1024)),
+    ]
+}
+
+// Offset and size of the largest file data in the archive.
+async fn largest_entry(target: &Path) -> (u64, u64) {
+    let mut r = tokio_tar::Archive::new(tokio::fs::File::open(target).await.unwrap());
+    let mut entries = r.entries().unwrap();
+    let mut largest = (0, 0);
+    while let Some(file) = entries.next().await {
+        let f = file.unwrap();
+        let size = f.header().size().unwrap();
+        let path = f.path().unwrap().to_string_lossy().to_string();
+        if path != MANIFEST_PATH && path != INDEX_PATH && size > largest.1 {
+            largest = (f.raw_file_position(), size);
+        }
+    }
+    largest
+}
+
+#[tokio::test]
+async fn roundtrip_encryption() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    create_source(&source, &source_files());
+    let key_file = dir.path().join("archive.key");
+    fs::write(&key_file, [7; 32]).unwrap();
+    let wrong_key_file = dir.path().join("wrong.key");
+    fs::write(&wrong_key_file, "ab".repeat(32)).unwrap();
+    let key = KeySource::File(key_file);
+    let target = dir.path().join("source.gz.tar");
+
+    for cipher in [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305] {
+        for names in [false, true] {
+            for stream in [false, true] {
+                archive(ArchiveParams {
+                    source: source.to_string_lossy().to_string(),
+                    target: target.to_string_lossy().to_string(),
+                    level: 6,
+                    pattern: "/**/*".to_string(),
+                    stream,
+                    encryption: Some(Encryption {
+                        cipher,
+                        key: key.clone(),
+                        names,
+                    }),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+                // neither the data nor the manifest reveals the content
+                let data = fs::read(&target).unwrap();
+                assert!(!data.windows(SECRET.len()).any(|w| w == SECRET));
+                assert!(!data.windows(10).any(|w| w == b"secret.txt") || !names);
+                assert!(data.windows(10).any(|w| w == b"secret.txt") || names);
+
+                unarchive_key(&target, &output, Some(key.clone()))
+                    .await
+                    .unwrap();
+                for file in ["hello.txt", "empty.txt", "sub/secret.txt"] {
+                    assert_eq!(
+                        fs::read(source.join(file)).unwrap(),
+                        fs::read(output.join(file)).unwrap(),
+                        "{file} is not the same"
+                    );
+                }
+
+                let items = list_with_key(&target.to_string_lossy(), Some(&key))
+                    .await
+                    .unwrap();
+                let item = items
+                    .iter()
+                    .find(|item| item.path == "sub/secret.txt")
+                    .unwrap();
+                assert_eq!(Some(SECRET.len() as u64 * 8 * 1024), item.size);
+                let items = verify(&target.to_string_lossy(), None, Some(&key))
+                    .await
+                    .unwrap();
+                assert_eq!(3, items.len());
+                assert!(items.iter().all(|item| item.is_valid()));
+
+                let result = unarchive_key(&target, &output, None).await;
+                assert!(matches!(result, Err(Error::InvalidKey { .. })));
+                let result = unarchive_key(
+                    &target,
+                    &output,
+                    Some(KeySource::File(wrong_key_file.clone())),
+                )
+                .await;
+                assert!(matches!(result, Err(Error::WrongKey)));
+            }
+        }
+    }
+
+    // the tampered data is not decrypted
+    let (offset, size) = largest_entry(&target).await;
+    let mut file = fs::OpenOptions::new().write(true).open(&target).unwrap();
+    file.seek(SeekFrom::Start(offset + size / 2)).unwrap();
+    file.write_all(&[0xff; 4]).unwrap();
+    drop(file);
+    let result = unarchive_key(&target, &output, Some(key.clone())).await;
+    assert!(matches!(result, Err(Error::Decrypt { .. })));
+    let items = verify(&target.to_string_lossy(), None, Some(&key))
+        .await
+        .unwrap();
+    assert_eq!(1, items.iter().filter(|item| !item.is_valid()).count());
+}
+
+#[tokio::test]
+async fn roundtrip_passphrase() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    create_source(&source, &source_files());
+    let target = dir.path().join("source.zst.tar");
+    archive(ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target.to_string_lossy().to_string(),
+        level: 3,
+        pattern: "/**/*".to_string(),
+        encryption: Some(Encryption {
+            cipher: Cipher::ChaCha20Poly1305,
+            key: KeySource::Passphrase("correct horse".to_string()),
+            names: true,
+        }),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    let result = unarchive_key(
+        &target,
+        &output,
+        Some(KeySource::Passphrase("battery staple".to_string())),
+    )
+    .await;
+    assert!(matches!(result, Err(Error::WrongKey)));
+    unarchive_key(
+        &target,
+        &output,
+        Some(KeySource::Passphrase("correct horse".to_string())),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        fs::read(source.join("sub/secret.txt")).unwrap(),
+        fs::read(output.join("sub/secret.txt")).unwrap()
+    );
+}
+
+#[tokio::test]
+async fn swapped_entries() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    fs::create_dir_all(&source).unwrap();
+    fs::write(source.join("a.txt"), SECRET).unwrap();
+    fs::write(source.join("b.txt"), SECRET).unwrap();
+    let target = dir.path().join("source.zst.tar");
+    let key = KeySource::Passphrase("secret".to_string());
+    archive(ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target.to_string_lossy().to_string(),
+        level: 3,
+        pattern: "/**/*".to_string(),
+        encryption: Some(Encryption {
+            cipher: Cipher::Aes256Gcm,
+            key: key.clone(),
+            names: false,
+        }),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    // the encrypted data of entries is bound to their paths
+    let mut data = fs::read(&target).unwrap();
+    let index = read_index(&target.to_string_lossy())
+        .await
+        .unwrap()
+        .unwrap();
+    let find = |path: &str| {
+        let entry = index.entries.iter().find(|e| e.path == path).unwrap();
+        (entry.offset as usize, entry.length as usize)
+    };
+    let (a, length) = find("a.txt");
+    let (b, b_length) = find("b.txt");
+    assert_eq!(length, b_length);
+    let a_data = data[a..a + length].to_vec();
+    data.copy_within(b..b + length, a);
+    data[b..b + length].copy_from_slice(&a_data);
+    fs::write(&target, data).unwrap();
+    let result = unarchive_key(&target, &output, Some(key)).await;
+    assert!(matches!(result, Err(Error::Decrypt { .. })));
+}
@@ -0,0 +1,272 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use archiver::{
+    ArchiveParams, Cipher, Encryption, Error, KeySource, UnarchiveParams, archive, read_manifest,
+    restore,
+};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+mod common;
+use common::{params, write_file};
+
+fn incremental_params(source: &Path, target: &Path, base: &Path) -> ArchiveParams {
+    ArchiveParams {
+        base: Some(base.to_string_lossy().to_string()),
+        ..params(source, target)
+    }
+}
+
+// Files and directories of the tree, directories have no content.
+fn read_tree(dir: &Path) -> BTreeMap<String, Option<Vec<u8>>> {
+    let mut tree = BTreeMap::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        for entry in fs::read_dir(&current).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path
+                .strip_prefix(dir)
+                .unwrap()
+                .to_string_lossy()
+                .to_string();
+            if path.is_dir() {
+                tree.insert(name, None);
+                dirs.push(path);
+            } else {
+                tree.insert(name, Some(fs::read(&path).unwrap()));
+            }
+        }
+    }
+    tree
+}
+
+async fn restore_chain(
+    chain: &[&PathBuf],
+    output: &Path,
+    key: Option<KeySource>,
+) -> Result<(), Error> {
+    let _ = fs::remove_dir_all(output);
+    let chain: Vec<String> = chain
+        .iter()
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+    restore(
+        &chain,
+        UnarchiveParams {
+            target: output.to_string_lossy().to_string(),
+            key,
+            ..Default::default()
+        },
+    )
+    .await
+}
+
+#[tokio::test]
+async fn incremental_chain() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let full = dir.path().join("full.zst.tar");
+    let first = dir.path().join("first.zst.tar");
+    let second = dir.path().join("second.zst.tar");
+
+    write_file(&source, "a.txt", "day 0", 1_700_000_000);
+    write_file(
+        &source,
+        "b.txt",
+        &"same content ".repeat(100),
+        1_700_000_000,
+    );
+    write_file(&source, "sub/c.txt", "removed later", 1_700_000_000);
+    archive(params(&source, &full)).await.unwrap();
+    let day0 = read_tree(&source);
+
+    // the content of b.txt is the same with new mtime
+    write_file(&source, "a.txt", "day 1", 1_700_086_400);
+    write_file(
+        &source,
+        "b.txt",
+        &"same content ".repeat(100),
+        1_700_086_400,
+    );
+    fs::remove_dir_all(source.join("sub")).unwrap();
+    write_file(&source, "d.txt", "added at day 1", 1_700_086_400);
+    archive(incremental_params(&source, &first, &full))
+        .await
+        .unwrap();
+    let day1 = read_tree(&source);
+
+    let manifest = read_manifest(&first.to_string_lossy())
+        .await
+        .unwrap()
+        .unwrap();
+    let full_manifest = read_manifest(&full.to_string_lossy())
+        .await
+        .unwrap()
+        .unwrap();
+    let incremental = manifest.incremental.clone().unwrap();
+    assert_eq!(full_manifest.checksum().unwrap(), incremental.base);
+    let mut stored: Vec<&str> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+    stored.sort();
+    assert_eq!(vec!["a.txt", "d.txt"], stored);
+    assert_eq!(
+        vec!["b.txt"],
+        incremental
+            .unchanged
+            .iter()
+            .map(|f| f.path.as_str())
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(vec!["sub", "sub/c.txt"], incremental.deleted);
+    assert_eq!(3, manifest.snapshot().len());
+
+    write_file(&source, "d.txt", "changed at day 2", 1_700_172_800);
+    archive(incremental_params(&source, &second, &first))
+        .await
+        .unwrap();
+    let day2 = read_tree(&source);
+    let manifest = read_manifest(&second.to_string_lossy())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        vec!["d.txt"],
+        manifest
+            .files
+            .iter()
+            .map(|f| f.path.as_str())
+            .collect::<Vec<_>>()
+    );
+    assert!(manifest.incremental.unwrap().deleted.is_empty());
+
+    for (chain, tree) in [
+        (vec![&full], &day0),
+        (vec![&full, &first], &day1),
+        (vec![&full, &first, &second], &day2),
+    ] {
+        restore_chain(&chain, &output, None).await.unwrap();
+        assert_eq!(*tree, read_tree(&output));
+    }
+
+    // the chain must start from the full archive in order
+    for chain in [
+        vec![&first],
+        vec![&full, &second],
+        vec![&full, &second, &first],
+        vec![&full, &full],
+    ] {
+        let result = restore_chain(&chain, &output, None).await;
+        assert!(
+            matches!(result, Err(Error::InvalidIncremental { .. })),
+            "{chain:?}"
+        );
+        assert!(!output.exists());
+    }
+}
+
+#[tokio::test]
+async fn incremental_from_manifest() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let full = dir.path().join("full.gz.tar");
+    let base = dir.path().join("full.json");
+    let incremental = dir.path().join("incremental.gz.tar");
+
+    write_file(&source, "a.txt", "unchanged", 1_700_000_000);
+    write_file(&source, "b.txt", "day 0", 1_700_000_000);
+    archive(params(&source, &full)).await.unwrap();
+    let manifest = read_manifest(&full.to_string_lossy())
+        .await
+        .unwrap()
+        .unwrap();
+    fs::write(&base, manifest.to_json().unwrap()).unwrap();
+
+    write_file(&source, "b.txt", "day 1", 1_700_086_400);
+    fs::remove_file(source.join("a.txt")).unwrap();
+    archive(incremental_params(&source, &incremental, &base))
+        .await
+        .unwrap();
+    let manifest = read_manifest(&incremental.to_string_lossy())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(vec!["a.txt"], manifest.incremental.unwrap().deleted);
+
+    restore_chain(&[&full, &incremental], &output, None)
+        .await
+        .unwrap();
+    assert_eq!(read_tree(&source), read_tree(&output));
+
+    let result = archive(ArchiveParams {
+        append: true,
+        ..incremental_params(&source, &incremental, &base)
+    })
+    .await;
+    assert!(matches!(result, Err(Error::InvalidArg { .. })));
+}
+
+#[tokio::test]
+async fn incremental_encrypted() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let full = dir.path().join("full.br.tar");
+    let incremental = dir.path().join("incremental.br.tar");
+    let key = KeySource::Passphrase("secret".to_string());
+    let encryption = Encryption {
+        cipher: Cipher::ChaCha20Poly1305,
+        key: key.clone(),
+        names: true,
+    };
+
+    write_file(&source, "a.txt", "unchanged", 1_700_000_000);
+    write_file(&source, "b.txt", "day 0", 1_700_000_000);
+    write_file(&source, "c.txt", "removed", 1_700_000_000);
+    archive(ArchiveParams {
+        encryption: Some(encryption.clone()),
+        ..params(&source, &full)
+    })
+    .await
+    .unwrap();
+
+    write_file(&source, "b.txt", "day 1", 1_700_086_400);
+    fs::remove_file(source.join("c.txt")).unwrap();
+    let result = archive(incremental_params(&source, &incremental, &full)).await;
+    assert!(matches!(result, Err(Error::InvalidKey { .. })));
+    archive(ArchiveParams {
+        encryption: Some(encryption),
+        ..incremental_params(&source, &incremental, &full)
+    })
+    .await
+    .unwrap();
+
+    // the tombstones are sealed with the files
+    let manifest = read_manifest(&incremental.to_string_lossy())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(manifest.sealed.is_some());
+    assert_eq!(None, manifest.incremental);
+    let data = fs::read(&incremental).unwrap();
+    assert!(!data.windows(5).any(|w| w == b"c.txt"));
+
+    restore_chain(&[&full, &incremental], &output, Some(key))
+        .await
+        .unwrap();
+    assert_eq!(read_tree(&source), read_tree(&output));
+}
@@ -0,0 +1,175 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use archiver::{ArchiveParams, Codec, Compression, INDEX_PATH, IndexedReader, archive, read_index};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use tempfile::TempDir;
+use tokio_stream::StreamExt;
+
+mod common;
+use common::{create_source, unarchive_file};
+
+const LONG_PATH: &str = "a-very-long-directory-name-which-is-longer-than-the-name-field/\
+                         of-the-tar-header/long-name.txt";
+
+fn source_files() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("hello.txt", b"hello world".to_vec()),
+        (
+            "sub/data.bin",
+            (0..128 * 1024u32).map(|i| (i * 7 % 251) as u8).collect(),
+        ),
+        (LONG_PATH, b"long name".repeat(100)),
+    ]
+}
+
+async fn read_file(target: &Path, path: &str) -> Option<Vec<u8>> {
+    let reader = IndexedReader::open(&target.to_string_lossy(), None, None)
+        .await
+        .unwrap();
+    let mut data = vec![];
+    reader
+        .read_file(path, &mut data)
+        .await
+        .unwrap()
+        .map(|size| {
+            assert_eq!(size, data.len() as u64);
+            data
+        })
+}
+
+#[tokio::test]
+async fn random_access_index() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let files = source_files();
+    create_source(&source, &files);
+    let target = dir.path().join("source.zst.tar");
+
+    for stream in [false, true] {
+        archive(ArchiveParams {
+            source: source.to_string_lossy().to_string(),
+            target: target.to_string_lossy().to_string(),
+            level: 3,
+            pattern: "/**/*".to_string(),
+            stream,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let index = read_index(&target.to_string_lossy())
+            .await
+            .unwrap()
+            .unwrap();
+        let data = fs::read(&target).unwrap();
+        for (path, content) in files.iter() {
+            let entry = index.entries.iter().find(|e| e.path == *path).unwrap();
+            assert_eq!("zst", entry.codec);
+            // the stored data is decoded from the offset
+            let mut stored = &data[entry.offset as usize..(entry.offset + entry.length) as usize];
+            let mut decoded = vec![];
+            Compression::Zstd
+                .decode(&mut stored, &mut decoded)
+                .await
+                .unwrap();
+            assert_eq!(*content, decoded, "{path}");
+            assert_eq!(Some(content.clone()), read_file(&target, path).await);
+            assert_eq!(*content, unarchive_file(&target, &output, path).await);
+        }
+        assert!(index.entries.iter().any(|e| e.path == "sub"));
+        assert_eq!(None, read_file(&target, "missing.txt").await);
+    }
+
+    // the entries before the file are not read with the index
+    let index = read_index(&target.to_string_lossy())
+        .await
+        .unwrap()
+        .unwrap();
+    let first = index
+        .entries
+        .iter()
+        .find(|e| e.path == "hello.txt")
+        .unwrap();
+    let mut file = fs::OpenOptions::new().write(true).open(&target).unwrap();
+    file.seek(SeekFrom::Start(first.header)).unwrap();
+    file.write_all(&[0xff; 512]).unwrap();
+    drop(file);
+    assert_eq!(
+        files[2].1,
+        unarchive_file(&target, &output, LONG_PATH).await
+    );
+    assert_eq!(
+        Some(files[2].1.clone()),
+        read_file(&target, LONG_PATH).await
+    );
+}
+
+#[tokio::test]
+async fn scan_without_index() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let files = source_files();
+    create_source(&source, &files);
+    let target = dir.path().join("source.gz.tar");
+    archive(ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target.to_string_lossy().to_string(),
+        level: 6,
+        pattern: "/**/*".to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    // remove the index entry, the archive ends with two zero blocks
+    let mut r = tokio_tar::Archive::new(tokio::fs::File::open(&target).await.unwrap());
+    let mut entries = r.entries().unwrap();
+    let mut offset = 0;
+    while let Some(file) = entries.next().await {
+        let f = file.unwrap();
+        if f.path().unwrap().to_string_lossy() == INDEX_PATH {
+            offset = f.raw_file_position() - 512;
+        }
+    }
+    assert!(offset > 0);
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&target)
+        .unwrap();
+    file.set_len(offset).unwrap();
+    file.seek(SeekFrom::End(0)).unwrap();
+    file.write_all(&[0; 1024]).unwrap();
+    let mut data = vec![];
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut data).unwrap();
+    assert!(
+        !data
+            .windows(INDEX_PATH.len())
+            .any(|w| w == INDEX_PATH.as_bytes())
+    );
+    drop(file);
+
+    assert_eq!(None, read_index(&target.to_string_lossy()).await.unwrap());
+    for (path, content) in files.iter() {
+        assert_eq!(Some(content.clone()), read_file(&target, path).await);
+        assert_eq!(*content, unarchive_file(&target, &output, path).await);
+    }
+    assert_eq!(None, read_file(&target, "missing.txt").await);
+}
@@ -0,0 +1,185 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use archiver::{ArchiveParams, Error, Limit, UnarchiveParams, archive};
+use std::fs;
+use tempfile::TempDir;
+use tokio::fs::File;
+use tokio_tar::{Builder, Header};
+
+mod common;
+use common::unarchive_with;
+
+#[tokio::test]
+async fn unarchive_limits() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    fs::create_dir_all(&source).unwrap();
+    fs::write(source.join("hello.txt"), b"hello world").unwrap();
+    // highly compressible data like a decompression bomb
+    fs::write(source.join("zeros.bin"), vec![0; 8 * 1024 * 1024]).unwrap();
+    let target = dir.path().join("source.zst.tar");
+    archive(ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target.to_string_lossy().to_string(),
+        level: 3,
+        pattern: "/**/*".to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    let result = unarchive_with(
+        &target,
+        &output,
+        UnarchiveParams {
+            max_entry_size: Some(1024 * 1024),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(Error::LimitExceeded {
+            limit: Limit::EntrySize(_),
+            ..
+        })
+    ));
+    // the partial file is removed
+    assert!(!output.join("zeros.bin").exists());
+
+    let result = unarchive_with(
+        &target,
+        &output,
+        UnarchiveParams {
+            max_total_size: Some(4 * 1024 * 1024),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(Error::LimitExceeded {
+            limit: Limit::TotalSize(_),
+            ..
+        })
+    ));
+
+    let result = unarchive_with(
+        &target,
+        &output,
+        UnarchiveParams {
+            max_ratio: Some(100.0),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(Error::LimitExceeded {
+            limit: Limit::Ratio(_),
+            ..
+        })
+    ));
+
+    let result = unarchive_with(
+        &target,
+        &output,
+        UnarchiveParams {
+            max_entries: Some(1),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(Error::LimitExceeded {
+            limit: Limit::Entries(1),
+            ..
+        })
+    ));
+
+    // the archive is extracted within the limits
+    unarchive_with(
+        &target,
+        &output,
+        UnarchiveParams {
+            max_entry_size: Some(8 * 1024 * 1024),
+            max_total_size: Some(16 * 1024 * 1024),
+            max_entries: Some(16),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        b"hello world".to_vec(),
+        fs::read(output.join("hello.txt")).unwrap()
+    );
+    assert_eq!(
+        8 * 1024 * 1024,
+        fs::metadata(output.join("zeros.bin")).unwrap().len()
+    );
+}
+
+#[tokio::test]
+async fn unarchive_legacy_block_limits() {
+    let dir = TempDir::new().unwrap();
+    let output = dir.path().join("output");
+    let target = dir.path().join("legacy.lz4.tar");
+    // size prepended block written by old version is decoded in memory
+    let data = lz4_flex::block::compress_prepend_size(&vec![0; 8 * 1024 * 1024]);
+    let mut a = Builder::new(File::create(&target).await.unwrap());
+    let mut header = Header::new_gnu();
+    header.set_path("zeros.bin").unwrap();
+    header.set_mode(0o644);
+    header.set_size(data.len() as u64);
+    header.set_cksum();
+    a.append(&header, &data[..]).await.unwrap();
+    a.finish().await.unwrap();
+
+    // the declared size is checked before the block is decompressed
+    for (params, expected) in [
+        (
+            UnarchiveParams {
+                max_entry_size: Some(1024 * 1024),
+                ..Default::default()
+            },
+            Limit::EntrySize(1024 * 1024),
+        ),
+        (
+            UnarchiveParams {
+                max_total_size: Some(4 * 1024 * 1024),
+                ..Default::default()
+            },
+            Limit::TotalSize(4 * 1024 * 1024),
+        ),
+    ] {
+        let result = unarchive_with(&target, &output, params).await;
+        assert!(matches!(
+            result,
+            Err(Error::LimitExceeded { limit, .. }) if limit == expected
+        ));
+        assert!(!output.join("zeros.bin").exists());
+    }
+
+    unarchive_with(&target, &output, UnarchiveParams::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        8 * 1024 * 1024,
+        fs::metadata(output.join("zeros.bin")).unwrap().len()
+    );
+}
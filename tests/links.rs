@@ -0,0 +1,82 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(unix)]
+
+use archiver::{ArchiveParams, UnarchiveParams, archive, list, unarchive};
+use std::fs;
+use std::os::unix::fs::{MetadataExt, symlink};
+use tempfile::TempDir;
+
+#[tokio::test]
+async fn roundtrip_links() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    fs::create_dir_all(source.join("empty")).unwrap();
+    fs::create_dir_all(source.join("sub")).unwrap();
+    fs::write(source.join("sub/hello.txt"), b"hello world").unwrap();
+    symlink("sub/hello.txt", source.join("hello.link")).unwrap();
+    symlink("sub", source.join("sub.link")).unwrap();
+    // the loop can't be followed
+    symlink(".", source.join("sub/self")).unwrap();
+    let long_link = format!("{}/hello.txt", "x".repeat(120));
+    symlink(&long_link, source.join("long.link")).unwrap();
+    fs::hard_link(source.join("sub/hello.txt"), source.join("hard.txt")).unwrap();
+    let target = dir.path().join("source.zst.tar");
+
+    for stream in [false, true] {
+        archive(ArchiveParams {
+            source: source.to_string_lossy().to_string(),
+            target: target.to_string_lossy().to_string(),
+            level: 3,
+            pattern: "/**/*".to_string(),
+            stream,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let items = list(&target.to_string_lossy()).await.unwrap();
+        assert!(!items.iter().any(|item| item.path.starts_with("sub.link/")));
+
+        let _ = fs::remove_dir_all(&output);
+        unarchive(UnarchiveParams {
+            source: target.to_string_lossy().to_string(),
+            target: output.to_string_lossy().to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        assert!(output.join("empty").is_dir());
+        assert_eq!(
+            b"hello world".to_vec(),
+            fs::read(output.join("sub/hello.txt")).unwrap()
+        );
+        for (link, expected) in [
+            ("hello.link", "sub/hello.txt"),
+            ("sub.link", "sub"),
+            ("sub/self", "."),
+            ("long.link", long_link.as_str()),
+        ] {
+            let path = output.join(link);
+            assert!(path.symlink_metadata().unwrap().file_type().is_symlink());
+            assert_eq!(expected, fs::read_link(path).unwrap().to_string_lossy());
+        }
+        let hard = fs::metadata(output.join("hard.txt")).unwrap();
+        let file = fs::metadata(output.join("sub/hello.txt")).unwrap();
+        assert_eq!(file.ino(), hard.ino());
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use archiver::{
+    ArchiveParams, Error, MANIFEST_VERSION, UnarchiveParams, archive, list, read_manifest,
+    unarchive,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+async fn create_archive(dir: &Path, name: &str, stream: bool) -> (PathBuf, PathBuf) {
+    let source = dir.join("source");
+    fs::create_dir_all(source.join("sub")).unwrap();
+    fs::write(source.join("hello.txt"), b"hello world").unwrap();
+    fs::write(source.join("sub/lorem.txt"), "Lorem ipsum ".repeat(100)).unwrap();
+    let target = dir.join(name);
+    archive(ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target.to_string_lossy().to_string(),
+        level: 3,
+        pattern: "/**/*".to_string(),
+        stream,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    (source, target)
+}
+
+#[tokio::test]
+async fn archive_manifest() {
+    for stream in [false, true] {
+        let dir = TempDir::new().unwrap();
+        let (source, target) = create_archive(dir.path(), "source.zst.tar", stream).await;
+
+        let manifest = read_manifest(&target.to_string_lossy())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(MANIFEST_VERSION, manifest.version);
+        assert_eq!("zst", manifest.compression);
+        assert_eq!(3, manifest.level);
+        assert_eq!(2, manifest.files.len());
+        for file in ["hello.txt", "sub/lorem.txt"] {
+            let data = fs::read(source.join(file)).unwrap();
+            let record = manifest.file(file).unwrap();
+            assert_eq!(data.len() as u64, record.size);
+            assert_eq!(blake3::hash(&data).to_hex().to_string(), record.checksum);
+            assert_eq!(0o644, record.mode & 0o777);
+        }
+    }
+}
+
+#[tokio::test]
+async fn unarchive_renamed() {
+    let dir = TempDir::new().unwrap();
+    let (source, target) = create_archive(dir.path(), "source.zst.tar", false).await;
+    let renamed = dir.path().join("backup");
+    fs::rename(&target, &renamed).unwrap();
+    let output = dir.path().join("output");
+
+    unarchive(UnarchiveParams {
+        source: renamed.to_string_lossy().to_string(),
+        target: output.to_string_lossy().to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(
+        fs::read(source.join("sub/lorem.txt")).unwrap(),
+        fs::read(output.join("sub/lorem.txt")).unwrap()
+    );
+    assert!(!output.join(archiver::MANIFEST_PATH).exists());
+}
+
+#[tokio::test]
+async fn unarchive_compression_mismatch() {
+    let dir = TempDir::new().unwrap();
+    let (_, target) = create_archive(dir.path(), "source.zst.tar", false).await;
+    let renamed = dir.path().join("source.gz.tar");
+    fs::rename(&target, &renamed).unwrap();
+
+    let result = unarchive(UnarchiveParams {
+        source: renamed.to_string_lossy().to_string(),
+        target: dir.path().join("output").to_string_lossy().to_string(),
+        ..Default::default()
+    })
+    .await;
+
+    assert!(matches!(
+        result,
+        Err(Error::CompressionMismatch { expected, actual }) if expected == "zst" && actual == "gz"
+    ));
+}
+
+#[tokio::test]
+async fn list_original_size() {
+    let dir = TempDir::new().unwrap();
+    let (source, target) = create_archive(dir.path(), "source.gz.tar", false).await;
+
+    let items = list(&target.to_string_lossy()).await.unwrap();
+    assert_eq!(3, items.len());
+    // the directory is not recorded in manifest
+    let files: Vec<_> = items
+        .into_iter()
+        .filter(|item| item.path != "sub")
+        .collect();
+    assert_eq!(2, files.len());
+    for item in files {
+        let size = fs::metadata(source.join(&item.path)).unwrap().len();
+        assert_eq!(Some(size), item.size);
+        assert!(item.compressed_size.is_some());
+        assert!(item.ratio().is_some());
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(unix)]
+
+use archiver::{ArchiveParams, UnarchiveParams, archive, unarchive};
+use filetime::{FileTime, set_file_times};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use tempfile::TempDir;
+
+#[tokio::test]
+async fn roundtrip_metadata() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    fs::create_dir_all(source.join("sub")).unwrap();
+    let script = source.join("sub/run.sh");
+    fs::write(&script, b"#!/bin/sh\necho hello\n").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o750)).unwrap();
+    let mtime = FileTime::from_unix_time(1_700_000_000, 123_456_789);
+    let atime = FileTime::from_unix_time(1_600_000_000, 987_654_321);
+    fs::set_permissions(source.join("sub"), fs::Permissions::from_mode(0o700)).unwrap();
+    let target = dir.path().join("source.zst.tar");
+
+    for stream in [false, true] {
+        // the access time is changed when the file is read
+        set_file_times(&script, atime, mtime).unwrap();
+        archive(ArchiveParams {
+            source: source.to_string_lossy().to_string(),
+            target: target.to_string_lossy().to_string(),
+            level: 3,
+            pattern: "/**/*".to_string(),
+            stream,
+            atime: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let _ = fs::remove_dir_all(&output);
+        unarchive(UnarchiveParams {
+            source: target.to_string_lossy().to_string(),
+            target: output.to_string_lossy().to_string(),
+            preserve_atime: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let meta = fs::metadata(output.join("sub/run.sh")).unwrap();
+        assert_eq!(0o750, meta.permissions().mode() & 0o7777);
+        assert_eq!(mtime, FileTime::from_last_modification_time(&meta));
+        assert_eq!(atime, FileTime::from_last_access_time(&meta));
+        let meta = fs::metadata(output.join("sub")).unwrap();
+        assert_eq!(0o700, meta.permissions().mode() & 0o7777);
+    }
+
+    let _ = fs::remove_dir_all(&output);
+    unarchive(UnarchiveParams {
+        source: target.to_string_lossy().to_string(),
+        target: output.to_string_lossy().to_string(),
+        preserve_permissions: false,
+        preserve_mtime: false,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let meta = fs::metadata(output.join("sub/run.sh")).unwrap();
+    assert_eq!(0, meta.permissions().mode() & 0o111);
+    assert_ne!(mtime, FileTime::from_last_modification_time(&meta));
+}
+
+#[tokio::test]
+async fn roundtrip_xattrs() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    fs::create_dir_all(&source).unwrap();
+    let file = source.join("hello.txt");
+    fs::write(&file, b"hello world").unwrap();
+    // user::rw-,user:1000:r--,group::r--,mask::r--,other::---
+    let acl: Vec<u8> = [
+        2u32.to_le_bytes().to_vec(),
+        [1u16.to_le_bytes(), 6u16.to_le_bytes()].concat(),
+        u32::MAX.to_le_bytes().to_vec(),
+        [2u16.to_le_bytes(), 4u16.to_le_bytes()].concat(),
+        1000u32.to_le_bytes().to_vec(),
+        [4u16.to_le_bytes(), 4u16.to_le_bytes()].concat(),
+        u32::MAX.to_le_bytes().to_vec(),
+        [0x10u16.to_le_bytes(), 4u16.to_le_bytes()].concat(),
+        u32::MAX.to_le_bytes().to_vec(),
+        [0x20u16.to_le_bytes(), 0u16.to_le_bytes()].concat(),
+        u32::MAX.to_le_bytes().to_vec(),
+    ]
+    .concat();
+    // the file system may not support them
+    if xattr::set(&file, "user.comment", b"\x00binary\nvalue").is_err()
+        || xattr::set(&file, "system.posix_acl_access", &acl).is_err()
+    {
+        return;
+    }
+    let target = dir.path().join("source.gz.tar");
+    archive(ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target.to_string_lossy().to_string(),
+        level: 3,
+        pattern: "/**/*".to_string(),
+        xattrs: true,
+        acls: true,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    unarchive(UnarchiveParams {
+        source: target.to_string_lossy().to_string(),
+        target: output.to_string_lossy().to_string(),
+        xattrs: true,
+        acls: true,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let file = output.join("hello.txt");
+    assert_eq!(
+        Some(b"\x00binary\nvalue".to_vec()),
+        xattr::get(&file, "user.comment").unwrap()
+    );
+    assert_eq!(
+        Some(acl),
+        xattr::get(&file, "system.posix_acl_access").unwrap()
+    );
+
+    // they are not restored by default
+    fs::remove_dir_all(&output).unwrap();
+    unarchive(UnarchiveParams {
+        source: target.to_string_lossy().to_string(),
+        target: output.to_string_lossy().to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        None,
+        xattr::get(output.join("hello.txt"), "user.comment").unwrap()
+    );
+}
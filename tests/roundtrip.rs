@@ -0,0 +1,287 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use archiver::{ArchiveParams, UnarchiveParams, archive, unarchive};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+mod common;
+use common::create_source;
+
+fn source_files() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("hello.txt", b"hello world".to_vec()),
+        ("empty.txt", vec![]),
+        (
+            "sub/lorem.txt",
+            "Lorem ipsum dolor sit amet. ".repeat(512).into_bytes(),
+        ),
+        (
+            "sub/deep/data.bin",
+            (0..4096u32).map(|i| (i * 7 % 251) as u8).collect(),
+        ),
+        ("large.bin", large_data(512 * 1024 + 17)),
+    ]
+}
+
+// Pseudo random data that is larger than any single read buffer and
+// does not compress down to a handful of bytes.
+fn large_data(size: usize) -> Vec<u8> {
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    (0..size)
+        .map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % 16) as u8 + b'a'
+        })
+        .collect()
+}
+
+fn assert_same_files(source: &Path, output: &Path, files: &[&str]) {
+    for file in files {
+        let expected = fs::read(source.join(file)).unwrap();
+        let actual = fs::read(output.join(file)).unwrap();
+        assert_eq!(expected, actual, "{file} is not the same");
+    }
+}
+
+async fn roundtrip(compression: &str) {
+    roundtrip_with(compression, false).await;
+    roundtrip_with(compression, true).await;
+}
+
+async fn roundtrip_with(compression: &str, stream: bool) {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    create_source(&source, &source_files());
+    let target = dir
+        .path()
+        .join(format!("source.2025-01-01.{compression}.tar"));
+
+    archive(ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target.to_string_lossy().to_string(),
+        level: 6,
+        pattern: "/**/*".to_string(),
+        stream,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    unarchive(UnarchiveParams {
+        source: target.to_string_lossy().to_string(),
+        target: output.to_string_lossy().to_string(),
+        file: "".to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    assert_same_files(
+        &source,
+        &output,
+        &[
+            "hello.txt",
+            "empty.txt",
+            "sub/lorem.txt",
+            "sub/deep/data.bin",
+            "large.bin",
+        ],
+    );
+}
+
+#[tokio::test]
+async fn roundtrip_gzip() {
+    roundtrip("gz").await;
+}
+
+#[tokio::test]
+async fn roundtrip_zstd() {
+    roundtrip("zst").await;
+}
+
+#[tokio::test]
+async fn roundtrip_brotli() {
+    roundtrip("br").await;
+}
+
+#[tokio::test]
+async fn roundtrip_deflate() {
+    roundtrip("zip").await;
+}
+
+#[tokio::test]
+async fn roundtrip_xz() {
+    roundtrip("xz").await;
+}
+
+#[tokio::test]
+async fn roundtrip_snappy() {
+    roundtrip("sz").await;
+}
+
+#[tokio::test]
+async fn roundtrip_lz4() {
+    roundtrip("lz4").await;
+}
+
+#[tokio::test]
+async fn unarchive_filter_file() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    create_source(&source, &source_files());
+    let target = dir.path().join("source.zst.tar");
+
+    archive(ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target.to_string_lossy().to_string(),
+        level: 6,
+        pattern: "/**/*".to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    unarchive(UnarchiveParams {
+        source: target.to_string_lossy().to_string(),
+        target: output.to_string_lossy().to_string(),
+        file: "sub/lorem.txt".to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    assert_same_files(&source, &output, &["sub/lorem.txt"]);
+    assert!(!output.join("hello.txt").exists());
+}
+
+// Archives written by old versions store snappy and lz4 entries as raw blocks.
+async fn unarchive_legacy(compression: &str, encode: fn(&[u8]) -> Vec<u8>) {
+    let dir = TempDir::new().unwrap();
+    let output = dir.path().join("output");
+    let target = dir.path().join(format!("legacy.{compression}.tar"));
+    let data = large_data(200 * 1024);
+
+    let file = tokio::fs::File::create(&target).await.unwrap();
+    let mut builder = tokio_tar::Builder::new(file);
+    let buf = encode(&data);
+    let mut header = tokio_tar::Header::new_gnu();
+    header.set_size(buf.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, "legacy.bin", &buf[..])
+        .await
+        .unwrap();
+    builder.finish().await.unwrap();
+    drop(builder);
+
+    unarchive(UnarchiveParams {
+        source: target.to_string_lossy().to_string(),
+        target: output.to_string_lossy().to_string(),
+        file: "".to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(data, fs::read(output.join("legacy.bin")).unwrap());
+}
+
+#[tokio::test]
+async fn unarchive_legacy_snappy() {
+    unarchive_legacy("sz", |data| {
+        snap::raw::Encoder::new().compress_vec(data).unwrap()
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn unarchive_legacy_lz4() {
+    unarchive_legacy("lz4", lz4_flex::block::compress_prepend_size).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn archive_jobs_reproducible() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    create_source(&source, &source_files());
+    for i in 0..16 {
+        fs::write(
+            source.join(format!("sub/{i}.txt")),
+            format!("{i}").repeat(i * 100),
+        )
+        .unwrap();
+    }
+
+    let mut archives = vec![];
+    for jobs in [1, 4] {
+        let target = dir.path().join(format!("source-{jobs}.br.tar"));
+        archive(ArchiveParams {
+            source: source.to_string_lossy().to_string(),
+            target: target.to_string_lossy().to_string(),
+            level: 6,
+            pattern: "/**/*".to_string(),
+            jobs,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        archives.push(fs::read(target).unwrap());
+    }
+    assert_eq!(archives[0], archives[1]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn unarchive_jobs() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    create_source(&source, &source_files());
+    let mut files = vec![];
+    for i in 0..32 {
+        let file = format!("sub/{i}.txt");
+        fs::write(source.join(&file), format!("{i}").repeat(i * 100)).unwrap();
+        files.push(file);
+    }
+    let target = dir.path().join("source.xz.tar");
+
+    archive(ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target.to_string_lossy().to_string(),
+        level: 6,
+        pattern: "/**/*".to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    unarchive(UnarchiveParams {
+        source: target.to_string_lossy().to_string(),
+        target: output.to_string_lossy().to_string(),
+        jobs: 4,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    let files: Vec<&str> = files.iter().map(|file| file.as_str()).collect();
+    assert_same_files(&source, &output, &files);
+    assert_same_files(&source, &output, &["large.bin", "sub/deep/data.bin"]);
+}
@@ -0,0 +1,157 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(unix)]
+
+use archiver::{Error, UnarchiveParams, gzip_encode, unarchive};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+use tokio::fs::File;
+use tokio_tar::{Builder, EntryType, Header};
+
+enum Entry<'a> {
+    File(&'a str, &'a [u8]),
+    Symlink(&'a str, &'a str),
+}
+
+// The path is written to the header as it is,
+// because `set_path` rejects the unsafe paths.
+fn set_name(header: &mut Header, name: &str) {
+    let field = &mut header.as_old_mut().name;
+    field[..name.len()].copy_from_slice(name.as_bytes());
+}
+
+async fn create_archive(target: &Path, entries: &[Entry<'_>]) {
+    let mut a = Builder::new(File::create(target).await.unwrap());
+    for entry in entries {
+        let mut header = Header::new_gnu();
+        header.set_mode(0o644);
+        match entry {
+            Entry::File(name, data) => {
+                let mut compressed = vec![];
+                gzip_encode(&mut &data[..], &mut compressed, 6)
+                    .await
+                    .unwrap();
+                set_name(&mut header, name);
+                header.set_size(compressed.len() as u64);
+                header.set_cksum();
+                a.append(&header, &compressed[..]).await.unwrap();
+            }
+            Entry::Symlink(name, link) => {
+                set_name(&mut header, name);
+                header.set_entry_type(EntryType::Symlink);
+                header.set_link_name(link).unwrap();
+                header.set_size(0);
+                header.set_cksum();
+                a.append(&header, tokio::io::empty()).await.unwrap();
+            }
+        }
+    }
+    a.finish().await.unwrap();
+}
+
+async fn unarchive_to(target: &Path, output: &Path, unsafe_paths: bool) -> Result<(), Error> {
+    unarchive(UnarchiveParams {
+        source: target.to_string_lossy().to_string(),
+        target: output.to_string_lossy().to_string(),
+        unsafe_paths,
+        ..Default::default()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn unarchive_unsafe_paths() {
+    let dir = TempDir::new().unwrap();
+    let output = dir.path().join("output/nested");
+    let outside = dir.path().join("outside");
+    fs::create_dir_all(&outside).unwrap();
+    let target = dir.path().join("evil.gz.tar");
+
+    for entries in [
+        vec![Entry::File("../evil.txt", b"evil")],
+        vec![Entry::File("sub/../../evil.txt", b"evil")],
+        vec![Entry::File(
+            &format!("{}/evil.txt", outside.to_string_lossy()),
+            b"evil",
+        )],
+        vec![
+            Entry::Symlink("link", outside.to_str().unwrap()),
+            Entry::File("link/evil.txt", b"evil"),
+        ],
+    ] {
+        let _ = fs::remove_dir_all(dir.path().join("output"));
+        create_archive(&target, &entries).await;
+        let result = unarchive_to(&target, &output, false).await;
+        assert!(matches!(result, Err(Error::UnsafePath { .. })));
+        assert!(!outside.join("evil.txt").exists());
+        assert!(!dir.path().join("output/evil.txt").exists());
+    }
+
+    // the existing symlink is replaced instead of written through
+    let _ = fs::remove_dir_all(dir.path().join("output"));
+    create_archive(
+        &target,
+        &[
+            Entry::Symlink(
+                "evil.txt",
+                &format!("{}/evil.txt", outside.to_string_lossy()),
+            ),
+            Entry::File("evil.txt", b"safe"),
+        ],
+    )
+    .await;
+    unarchive_to(&target, &output, false).await.unwrap();
+    assert!(!outside.join("evil.txt").exists());
+    assert_eq!(b"safe".to_vec(), fs::read(output.join("evil.txt")).unwrap());
+
+    // trusted archive
+    create_archive(&target, &[Entry::File("../trusted.txt", b"trusted")]).await;
+    unarchive_to(&target, &output, true).await.unwrap();
+    assert_eq!(
+        b"trusted".to_vec(),
+        fs::read(dir.path().join("output/trusted.txt")).unwrap()
+    );
+}
+
+#[tokio::test]
+async fn unarchive_symlink_after_parallel_file() {
+    let dir = TempDir::new().unwrap();
+    let output = dir.path().join("output");
+    let outside = dir.path().join("outside");
+    fs::create_dir_all(&outside).unwrap();
+    let target = dir.path().join("evil.gz.tar");
+    create_archive(
+        &target,
+        &[
+            Entry::File("a/b", b"evil"),
+            Entry::Symlink("a", outside.to_str().unwrap()),
+        ],
+    )
+    .await;
+
+    // the file decoded by workers must not be written through the symlink
+    for _ in 0..20 {
+        let _ = fs::remove_dir_all(&output);
+        let _ = unarchive(UnarchiveParams {
+            source: target.to_string_lossy().to_string(),
+            target: output.to_string_lossy().to_string(),
+            jobs: 4,
+            ..Default::default()
+        })
+        .await;
+        assert!(!outside.join("b").exists());
+    }
+}
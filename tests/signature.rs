@@ -0,0 +1,107 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use archiver::{
+    ArchiveParams, ArchiveSignature, Error, archive, fingerprint, sign, verify_signature,
+};
+use ed25519_dalek::SigningKey;
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use tempfile::TempDir;
+use tokio_stream::StreamExt;
+
+async fn entry_offset(target: &Path, name: &str) -> u64 {
+    let mut r = tokio_tar::Archive::new(tokio::fs::File::open(target).await.unwrap());
+    let mut entries = r.entries().unwrap();
+    while let Some(file) = entries.next().await {
+        let f = file.unwrap();
+        if f.path().unwrap().to_string_lossy() == name {
+            return f.raw_file_position();
+        }
+    }
+    panic!("{name} is not found");
+}
+
+#[tokio::test]
+async fn sign_and_verify_signature() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    fs::create_dir_all(&source).unwrap();
+    fs::write(source.join("hello.txt"), b"hello world").unwrap();
+    fs::write(
+        source.join("lorem.txt"),
+        "Lorem ipsum dolor sit amet. ".repeat(512),
+    )
+    .unwrap();
+    let target = dir.path().join("source.zst.tar");
+    let target_str = target.to_string_lossy().to_string();
+    archive(ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target_str.clone(),
+        level: 3,
+        pattern: "/**/*".to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    let secret = [9; 32];
+    let key_file = dir.path().join("sign.key");
+    fs::write(&key_file, secret).unwrap();
+    let public_key = SigningKey::from_bytes(&secret).verifying_key().to_bytes();
+    let public_key_file = dir.path().join("sign.pub");
+    let hex: String = public_key.iter().map(|b| format!("{b:02x}")).collect();
+    fs::write(&public_key_file, format!("{hex}\n")).unwrap();
+    let other_key_file = dir.path().join("other.pub");
+    fs::write(&other_key_file, [1; 32]).unwrap();
+
+    let expected = fingerprint(&public_key);
+    assert_eq!(expected, sign(&target_str, &key_file, None).await.unwrap());
+    assert!(dir.path().join("source.zst.tar.sig").exists());
+    assert_eq!(
+        expected,
+        verify_signature(&target_str, None, None).await.unwrap()
+    );
+    assert_eq!(
+        expected,
+        verify_signature(&target_str, None, Some(&public_key_file))
+            .await
+            .unwrap()
+    );
+    let result = verify_signature(&target_str, None, Some(&other_key_file)).await;
+    assert!(matches!(result, Err(Error::UntrustedSigner { .. })));
+
+    // the signed digests are modified
+    let signature_file = dir.path().join("custom.sig");
+    let signature_str = signature_file.to_string_lossy().to_string();
+    sign(&target_str, &key_file, Some(&signature_str))
+        .await
+        .unwrap();
+    let mut signature: ArchiveSignature =
+        serde_json::from_slice(&fs::read(&signature_file).unwrap()).unwrap();
+    signature.entries.pop();
+    fs::write(&signature_file, serde_json::to_vec(&signature).unwrap()).unwrap();
+    let result = verify_signature(&target_str, Some(&signature_str), None).await;
+    assert!(matches!(result, Err(Error::InvalidSignature { .. })));
+
+    // the archive is modified
+    let offset = entry_offset(&target, "lorem.txt").await;
+    let mut file = fs::OpenOptions::new().write(true).open(&target).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(b"modified").unwrap();
+    drop(file);
+    let result = verify_signature(&target_str, None, None).await;
+    assert!(matches!(result, Err(Error::InvalidSignature { .. })));
+}
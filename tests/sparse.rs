@@ -0,0 +1,124 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(target_os = "linux")]
+
+use archiver::{ArchiveParams, Error, UnarchiveParams, archive, read_manifest, unarchive, verify};
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use tempfile::TempDir;
+
+const SIZE: u64 = 16 * 1024 * 1024;
+
+#[tokio::test]
+async fn roundtrip_sparse() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    fs::create_dir_all(&source).unwrap();
+    let image = source.join("disk.img");
+    let mut file = fs::File::create(&image).unwrap();
+    file.set_len(SIZE).unwrap();
+    file.write_all(b"boot sector").unwrap();
+    file.seek(SeekFrom::Start(SIZE / 2)).unwrap();
+    file.write_all(&[7; 4096]).unwrap();
+    drop(file);
+    // the file system may not support holes
+    if fs::metadata(&image).unwrap().blocks() * 512 >= SIZE {
+        return;
+    }
+    let data = fs::read(&image).unwrap();
+    let target = dir.path().join("source.zst.tar");
+
+    for stream in [false, true] {
+        archive(ArchiveParams {
+            source: source.to_string_lossy().to_string(),
+            target: target.to_string_lossy().to_string(),
+            level: 3,
+            pattern: "/**/*".to_string(),
+            stream,
+            sparse: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        // the holes are not compressed
+        assert!(fs::metadata(&target).unwrap().len() < 64 * 1024);
+        let manifest = read_manifest(&target.to_string_lossy())
+            .await
+            .unwrap()
+            .unwrap();
+        let record = manifest.file("disk.img").unwrap();
+        assert_eq!(SIZE, record.size);
+        assert_eq!(blake3::hash(&data).to_hex().to_string(), record.checksum);
+        // the holes are hashed as zeros when verify
+        let items = verify(&target.to_string_lossy(), None, None).await.unwrap();
+        assert!(items.iter().all(|item| item.is_valid()));
+
+        let _ = fs::remove_dir_all(&output);
+        unarchive(UnarchiveParams {
+            source: target.to_string_lossy().to_string(),
+            target: output.to_string_lossy().to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let meta = fs::metadata(output.join("disk.img")).unwrap();
+        assert_eq!(SIZE, meta.len());
+        assert!(meta.blocks() * 512 < SIZE);
+        assert!(data == fs::read(output.join("disk.img")).unwrap());
+    }
+}
+
+#[tokio::test]
+async fn unarchive_sparse_limits() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    fs::create_dir_all(&source).unwrap();
+    let image = source.join("disk.img");
+    let mut file = fs::File::create(&image).unwrap();
+    file.set_len(SIZE).unwrap();
+    file.write_all(b"boot sector").unwrap();
+    drop(file);
+    if fs::metadata(&image).unwrap().blocks() * 512 >= SIZE {
+        return;
+    }
+    let target = dir.path().join("source.zst.tar");
+    archive(ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target.to_string_lossy().to_string(),
+        level: 3,
+        pattern: "/**/*".to_string(),
+        sparse: true,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    // the holes are counted by the limits
+    for (max_entry_size, max_total_size) in [(Some(SIZE / 2), None), (None, Some(SIZE / 2))] {
+        let result = unarchive(UnarchiveParams {
+            source: target.to_string_lossy().to_string(),
+            target: output.to_string_lossy().to_string(),
+            max_entry_size,
+            max_total_size,
+            ..Default::default()
+        })
+        .await;
+        assert!(matches!(result, Err(Error::LimitExceeded { .. })));
+        assert!(!output.join("disk.img").exists());
+    }
+}
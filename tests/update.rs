@@ -0,0 +1,243 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use archiver::{
+    ArchiveParams, Cipher, Encryption, Error, KeySource, archive, delete, list_with_key,
+    read_index, read_manifest, update, verify,
+};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+mod common;
+use common::{params, unarchive_all, write_file};
+
+// Raw bytes of the entry from its first header to the end of data.
+async fn raw_entry(target: &Path, path: &str) -> Vec<u8> {
+    let index = read_index(&target.to_string_lossy())
+        .await
+        .unwrap()
+        .unwrap();
+    let entry = index.entries.iter().find(|e| e.path == path).unwrap();
+    let data = fs::read(target).unwrap();
+    data[entry.header as usize..(entry.offset + entry.length) as usize].to_vec()
+}
+
+async fn paths(target: &Path, key: Option<&KeySource>) -> Vec<String> {
+    let mut paths: Vec<String> = list_with_key(&target.to_string_lossy(), key)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.path)
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[tokio::test]
+async fn update_changed_files() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let target = dir.path().join("source.zst.tar");
+    write_file(&source, "a.txt", "first version", 1_700_000_000);
+    write_file(&source, "b.txt", &"unchanged ".repeat(100), 1_700_000_000);
+    write_file(&source, "sub/c.txt", "in sub directory", 1_700_000_000);
+    archive(params(&source, &target)).await.unwrap();
+    let unchanged = raw_entry(&target, "b.txt").await;
+
+    // nothing is rewritten without changes
+    let data = fs::read(&target).unwrap();
+    update(params(&source, &target)).await.unwrap();
+    assert_eq!(data, fs::read(&target).unwrap());
+
+    write_file(&source, "a.txt", "second version", 1_700_000_100);
+    write_file(&source, "d.txt", "new file", 1_700_000_100);
+    update(params(&source, &target)).await.unwrap();
+
+    // the unchanged entry is copied verbatim
+    assert_eq!(unchanged, raw_entry(&target, "b.txt").await);
+    unarchive_all(&target, &output, None).await;
+    for (path, data) in [
+        ("a.txt", "second version".to_string()),
+        ("b.txt", "unchanged ".repeat(100)),
+        ("sub/c.txt", "in sub directory".to_string()),
+        ("d.txt", "new file".to_string()),
+    ] {
+        assert_eq!(data.as_bytes(), fs::read(output.join(path)).unwrap());
+    }
+    let target_str = target.to_string_lossy().to_string();
+    let manifest = read_manifest(&target_str).await.unwrap().unwrap();
+    assert_eq!(4, manifest.files.len());
+    assert_eq!(14, manifest.file("a.txt").unwrap().size);
+    let items = verify(&target_str, None, None).await.unwrap();
+    assert_eq!(4, items.len());
+    assert!(
+        items
+            .iter()
+            .all(|item| item.is_valid() && item.expected.is_some())
+    );
+
+    // the removed file is kept, the size change is detected with the same mtime
+    fs::remove_file(source.join("d.txt")).unwrap();
+    write_file(&source, "a.txt", "third", 1_700_000_100);
+    update(params(&source, &target)).await.unwrap();
+    assert_eq!(
+        vec!["a.txt", "b.txt", "d.txt", "sub", "sub/c.txt"],
+        paths(&target, None).await
+    );
+    unarchive_all(&target, &output, None).await;
+    assert_eq!(b"third".to_vec(), fs::read(output.join("a.txt")).unwrap());
+}
+
+#[tokio::test]
+async fn delete_entries() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let target = dir.path().join("source.gz.tar");
+    write_file(&source, "a.txt", "file a", 1_700_000_000);
+    write_file(&source, "b.txt", &"file b".repeat(100), 1_700_000_000);
+    write_file(&source, "sub/c.txt", "file c", 1_700_000_000);
+    write_file(&source, "sub.txt", "not under sub", 1_700_000_000);
+    archive(params(&source, &target)).await.unwrap();
+    let unchanged = raw_entry(&target, "b.txt").await;
+    let target_str = target.to_string_lossy().to_string();
+
+    let data = fs::read(&target).unwrap();
+    let result = delete(
+        &target_str,
+        &["a.txt".to_string(), "missing.txt".to_string()],
+        None,
+        None,
+    )
+    .await;
+    assert!(matches!(result, Err(Error::PathNotExists { .. })));
+    assert_eq!(data, fs::read(&target).unwrap());
+
+    let count = delete(
+        &target_str,
+        &["a.txt".to_string(), "sub/".to_string()],
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(3, count);
+    assert_eq!(vec!["b.txt", "sub.txt"], paths(&target, None).await);
+    assert_eq!(unchanged, raw_entry(&target, "b.txt").await);
+    unarchive_all(&target, &output, None).await;
+    assert!(!output.join("a.txt").exists());
+    assert!(!output.join("sub").exists());
+    assert_eq!(
+        "file b".repeat(100).as_bytes(),
+        fs::read(output.join("b.txt")).unwrap()
+    );
+    let manifest = read_manifest(&target_str).await.unwrap().unwrap();
+    assert_eq!(2, manifest.files.len());
+    let items = verify(&target_str, None, None).await.unwrap();
+    assert!(
+        items
+            .iter()
+            .all(|item| item.is_valid() && item.expected.is_some())
+    );
+}
+
+#[tokio::test]
+async fn delete_appended_entries() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let target = dir.path().join("source.lz4.tar");
+    write_file(&source, "a.txt", "first", 1_700_000_000);
+    write_file(&source, "b.txt", "file b", 1_700_000_000);
+    archive(params(&source, &target)).await.unwrap();
+    fs::remove_dir_all(&source).unwrap();
+    write_file(&source, "a.txt", "second", 1_700_000_100);
+    archive(ArchiveParams {
+        append: true,
+        ..params(&source, &target)
+    })
+    .await
+    .unwrap();
+
+    // the replaced entry is dropped with the deleted one
+    let target_str = target.to_string_lossy().to_string();
+    assert_eq!(
+        1,
+        delete(&target_str, &["b.txt".to_string()], None, None)
+            .await
+            .unwrap()
+    );
+    let index = read_index(&target_str).await.unwrap().unwrap();
+    assert_eq!(
+        vec!["a.txt"],
+        index
+            .entries
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(None, index.manifest);
+    let output = dir.path().join("output");
+    unarchive_all(&target, &output, None).await;
+    assert_eq!(b"second".to_vec(), fs::read(output.join("a.txt")).unwrap());
+}
+
+#[tokio::test]
+async fn update_encrypted() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let target = dir.path().join("source.br.tar");
+    let key = KeySource::Passphrase("secret".to_string());
+    let encryption = Encryption {
+        cipher: Cipher::Aes256Gcm,
+        key: key.clone(),
+        names: true,
+    };
+    write_file(&source, "a.txt", "file a", 1_700_000_000);
+    write_file(&source, "b.txt", "file b", 1_700_000_000);
+    archive(ArchiveParams {
+        encryption: Some(encryption.clone()),
+        ..params(&source, &target)
+    })
+    .await
+    .unwrap();
+
+    write_file(&source, "a.txt", "file a again", 1_700_000_100);
+    let result = update(params(&source, &target)).await;
+    assert!(matches!(result, Err(Error::InvalidKey { .. })));
+    update(ArchiveParams {
+        encryption: Some(encryption),
+        ..params(&source, &target)
+    })
+    .await
+    .unwrap();
+    unarchive_all(&target, &output, Some(key.clone())).await;
+    assert_eq!(
+        b"file a again".to_vec(),
+        fs::read(output.join("a.txt")).unwrap()
+    );
+
+    let target_str = target.to_string_lossy().to_string();
+    let result = delete(&target_str, &["b.txt".to_string()], None, None).await;
+    assert!(matches!(result, Err(Error::InvalidKey { .. })));
+    delete(&target_str, &["b.txt".to_string()], None, Some(&key))
+        .await
+        .unwrap();
+    assert_eq!(vec!["a.txt"], paths(&target, Some(&key)).await);
+    let items = verify(&target_str, None, Some(&key)).await.unwrap();
+    assert_eq!(1, items.len());
+    assert!(items[0].is_valid());
+}
@@ -0,0 +1,109 @@
+// Copyright 2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use archiver::{ArchiveParams, Error, archive, verify};
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use tempfile::TempDir;
+use tokio_stream::StreamExt;
+
+// Offset and size of the entry data in the archive.
+async fn entry_position(target: &Path, name: &str) -> (u64, u64) {
+    let mut r = tokio_tar::Archive::new(tokio::fs::File::open(target).await.unwrap());
+    let mut entries = r.entries().unwrap();
+    while let Some(file) = entries.next().await {
+        let f = file.unwrap();
+        if f.path().unwrap().to_string_lossy() == name {
+            return (f.raw_file_position(), f.header().size().unwrap());
+        }
+    }
+    panic!("{name} is not found");
+}
+
+async fn verify_corrupted(compression: &str) {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    fs::create_dir_all(&source).unwrap();
+    fs::write(source.join("hello.txt"), b"hello world").unwrap();
+    let data: Vec<u8> = (0..256 * 1024u32).map(|i| (i * 7 % 251) as u8).collect();
+    fs::write(source.join("data.bin"), data).unwrap();
+    let target = dir.path().join(format!("source.{compression}.tar"));
+    archive(ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target.to_string_lossy().to_string(),
+        level: 3,
+        pattern: "/**/*".to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    let items = verify(&target.to_string_lossy(), None, None).await.unwrap();
+    assert_eq!(2, items.len());
+    assert!(items.iter().all(|item| item.is_valid()));
+    assert!(items.iter().all(|item| item.expected.is_some()));
+
+    // flip the bytes in the middle of the compressed data
+    let (offset, size) = entry_position(&target, "data.bin").await;
+    let mut file = fs::OpenOptions::new().write(true).open(&target).unwrap();
+    file.seek(SeekFrom::Start(offset + size / 2)).unwrap();
+    file.write_all(&[0xff; 16]).unwrap();
+    drop(file);
+
+    let items = verify(&target.to_string_lossy(), None, None).await.unwrap();
+    let corrupted: Vec<_> = items.iter().filter(|item| !item.is_valid()).collect();
+    assert_eq!(1, corrupted.len(), "{compression}");
+    assert_eq!("data.bin", corrupted[0].path);
+    assert!(
+        items
+            .iter()
+            .any(|item| item.path == "hello.txt" && item.is_valid())
+    );
+}
+
+#[tokio::test]
+async fn verify_archive() {
+    for compression in ["gz", "zst", "br", "xz", "lz4", "snappy"] {
+        verify_corrupted(compression).await;
+    }
+}
+
+#[tokio::test]
+async fn verify_appending() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    fs::create_dir_all(&source).unwrap();
+    fs::write(source.join("hello.txt"), b"hello world").unwrap();
+    let target = dir.path().join("source.zst.tar");
+    let target_str = target.to_string_lossy().to_string();
+    archive(ArchiveParams {
+        source: source.to_string_lossy().to_string(),
+        target: target_str.clone(),
+        level: 3,
+        pattern: "/**/*".to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    // the archive and the marker of the append are not modified
+    let marker = dir.path().join("source.zst.tar.appending");
+    let data = fs::read(&target).unwrap();
+    fs::write(&marker, 512u64.to_le_bytes()).unwrap();
+    let result = verify(&target_str, None, None).await;
+    assert!(matches!(result, Err(Error::AppendInProgress { .. })));
+    assert_eq!(data, fs::read(&target).unwrap());
+    assert_eq!(512u64.to_le_bytes()[..], fs::read(&marker).unwrap());
+}